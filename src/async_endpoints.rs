@@ -0,0 +1,198 @@
+//! `Future`s for queue endpoints, driven by the queue's eventfds through the
+//! tokio reactor instead of blocking a runtime worker.
+
+use nix::Error;
+use nix::Errno;
+use nix::libc;
+use ::queue::{EndpointError, Full, Receiver, Sender};
+use tokio::io::unix::AsyncFd;
+
+use std::future::Future;
+use std::io;
+use std::os::unix::io::{BorrowedFd, OwnedFd, RawFd};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+#[allow(dead_code)]
+impl<T, const N: usize> Sender<T, N>
+    where T: Copy
+{
+    /// Pushes `value`, waiting while the queue is full. Fails with
+    /// `Disconnected` once all receivers are gone.
+    ///
+    /// The queue must be created by `ipc_queue_with_eventfd`, and the future
+    /// polled within a tokio runtime.
+    pub fn send(&self, value: T) -> SendFuture<'_, T, N> {
+        SendFuture { sender: self, value, fd: None }
+    }
+}
+
+#[allow(dead_code)]
+impl<T, const N: usize> Receiver<T, N>
+    where T: Copy
+{
+    /// Pops a value, waiting while the queue is empty. Fails with
+    /// `Disconnected` once the queue is empty and all senders are gone.
+    ///
+    /// The queue must be created by `ipc_queue_with_eventfd`, and the future
+    /// polled within a tokio runtime.
+    pub fn recv(&self) -> RecvFuture<'_, T, N> {
+        RecvFuture { receiver: self, fd: None }
+    }
+}
+
+/// Future returned by `Sender::send`.
+pub struct SendFuture<'a, T, const N: usize>
+    where T: Copy
+{
+    sender: &'a Sender<T, N>,
+    value: T,
+    fd: Option<AsyncFd<OwnedFd>>
+}
+
+// `value` is only ever copied out, never pinned.
+impl<'a, T, const N: usize> Unpin for SendFuture<'a, T, N>
+    where T: Copy
+{}
+
+impl<'a, T, const N: usize> Future for SendFuture<'a, T, N>
+    where T: Copy
+{
+    type Output = Result<(), EndpointError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        let sender = this.sender;
+        let value = this.value;
+        poll_until(&mut this.fd, cx, || sender.as_raw_fd(), || {
+            Ok(match sender.try_push(value)? {
+                Ok(()) => Some(()),
+                Err(Full(_)) => None
+            })
+        })
+    }
+}
+
+/// Future returned by `Receiver::recv`.
+pub struct RecvFuture<'a, T, const N: usize>
+    where T: Copy
+{
+    receiver: &'a Receiver<T, N>,
+    fd: Option<AsyncFd<OwnedFd>>
+}
+
+impl<'a, T, const N: usize> Future for RecvFuture<'a, T, N>
+    where T: Copy
+{
+    type Output = Result<T, EndpointError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        let receiver = this.receiver;
+        poll_until(&mut this.fd, cx, || receiver.as_raw_fd(), || receiver.try_pop())
+    }
+}
+
+/// Retries `attempt` until it succeeds, waiting for the eventfd returned by
+/// `eventfd` to become readable in between.
+///
+/// The eventfd is only registered with the reactor once `attempt` fails, so
+/// calls which don't have to wait never touch it.
+fn poll_until<R, F, A>(fd: &mut Option<AsyncFd<OwnedFd>>, cx: &mut Context<'_>, eventfd: F, mut attempt: A)
+    -> Poll<Result<R, EndpointError>>
+    where F: FnOnce() -> Option<RawFd>, A: FnMut() -> Result<Option<R>, EndpointError>
+{
+    if fd.is_none() {
+        if let Some(result) = attempt()? {
+            return Poll::Ready(Ok(result));
+        }
+        *fd = Some(register(eventfd())?);
+    }
+    let fd = fd.as_ref().expect("eventfd registered above");
+
+    loop {
+        let mut guard = match fd.poll_read_ready(cx) {
+            Poll::Ready(guard) => guard.map_err(sys_error)?,
+            Poll::Pending => return Poll::Pending
+        };
+        // Readiness is only cleared if no new event arrived since it was
+        // reported, so a value which shows up after the attempt still wakes us.
+        match attempt()? {
+            Some(result) => return Poll::Ready(Ok(result)),
+            None => guard.clear_ready()
+        }
+    }
+}
+
+/// Registers a duplicate of `eventfd` with the reactor, so several futures
+/// may wait on the same queue at once.
+fn register(eventfd: Option<RawFd>) -> Result<AsyncFd<OwnedFd>, Error> {
+    let eventfd = eventfd.ok_or(Error::Sys(Errno::EINVAL))?;
+    let fd = unsafe { BorrowedFd::borrow_raw(eventfd) }
+        .try_clone_to_owned()
+        .map_err(sys_error)?;
+    AsyncFd::new(fd).map_err(sys_error)
+}
+
+fn sys_error(err: io::Error) -> Error {
+    Error::Sys(Errno::from_i32(err.raw_os_error().unwrap_or(libc::EIO)))
+}
+
+#[cfg(test)]
+mod tests {
+    use ::queue::{ipc_queue, ipc_queue_with_eventfd, EndpointError};
+    use ::process;
+    use nix::Error;
+    use nix::Errno;
+    use tokio::runtime::{Builder, Runtime};
+    use std::thread;
+    use std::time::Duration;
+
+    fn runtime() -> Runtime {
+        Builder::new_current_thread().enable_io().build().unwrap()
+    }
+
+    #[test]
+    fn recv() {
+        let (tx, rx) = ipc_queue_with_eventfd::<_, 8>().unwrap();
+        process::spawn(move || {
+            thread::sleep(Duration::from_millis(20));
+            for i in 0..100 {
+                tx.push(i).unwrap();
+            }
+        }).unwrap();
+
+        let rt = runtime();
+        for i in 0..100 {
+            assert_eq!(Ok(i), rt.block_on(rx.recv()));
+        }
+        assert_eq!(Err(EndpointError::Disconnected), rt.block_on(rx.recv()));
+    }
+
+    #[test]
+    fn send() {
+        let (tx, rx) = ipc_queue_with_eventfd::<_, 8>().unwrap();
+        let child = process::spawn(move || {
+            thread::sleep(Duration::from_millis(20));
+            for i in 0..100 {
+                assert_eq!(Ok(i), rx.pop());
+            }
+        }).unwrap();
+
+        let rt = runtime();
+        for i in 0..100 {
+            assert_eq!(Ok(()), rt.block_on(tx.send(i)));
+        }
+        child.wait(None).unwrap();
+        assert_eq!(Err(EndpointError::Disconnected), rt.block_on(tx.send(100)));
+    }
+
+    #[test]
+    fn without_eventfd() {
+        let (tx, rx) = ipc_queue::<i32, 8>().unwrap();
+        let rt = runtime();
+        assert_eq!(Ok(()), rt.block_on(tx.send(1)));
+        assert_eq!(Ok(1), rt.block_on(rx.recv()));
+        assert_eq!(Err(EndpointError::Sys(Error::Sys(Errno::EINVAL))), rt.block_on(rx.recv()));
+    }
+}
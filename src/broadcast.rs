@@ -0,0 +1,516 @@
+use nix::Error;
+use nix::Errno;
+use ::shm::{Checkpoint, PlainData, Shm};
+use ::process;
+use ::futex::Event;
+use ::pthread::PthreadPrimitiveConstructor;
+use ::pthread::PthreadWrappingPrimitiveConstructor;
+use ::pthread::Mutex;
+use ::pthread::MutexGuard;
+use ::queue::RING_BUFFER_SIZE;
+use ::spsc::CachePadded;
+use ::layout::{SharedLayout, TypeLayout};
+
+use std::cell::UnsafeCell;
+use std::mem;
+use std::mem::MaybeUninit;
+use std::ptr;
+use std::sync::atomic::{fence, AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+/// Maximum number of simultaneous subscribers of a `Broadcast`.
+pub const MAX_SUBSCRIBERS: usize = 16;
+
+/// Cursor of a free subscriber entry.
+const FREE: u64 = u64::MAX;
+
+/// What happens when a subscriber falls `N` messages behind the producers.
+#[repr(u8)]
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum SlowReaderPolicy {
+    /// Producers wait until the slowest subscriber catches up.
+    Block,
+    /// Producers overwrite old messages, and the lapped subscriber gets
+    /// `RecvError::Lagged` with the number of messages it missed.
+    Lag
+}
+
+unsafe impl SharedLayout for SlowReaderPolicy {
+    fn layout() -> TypeLayout {
+        TypeLayout::new::<Self>("SlowReaderPolicy")
+    }
+}
+
+unsafe impl PlainData for SlowReaderPolicy {}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum RecvError {
+    Lagged(u64),
+    Sys(Error)
+}
+
+impl From<Error> for RecvError {
+    fn from(err: Error) -> Self {
+        RecvError::Sys(err)
+    }
+}
+
+/// Broadcast ring of `N` messages: every subscriber sees every message
+/// published after it subscribed, reading at its own cursor.
+///
+/// Laid out like a Disruptor: messages are numbered by a sequence, the
+/// message with sequence `s` lives in slot `s % N`, and every subscriber
+/// keeps the sequence it reads next in an atomic cursor in the segment.
+/// Producers take turns under the `producer` lock to write the next slot,
+/// which is also where they wait for slow subscribers with
+/// `SlowReaderPolicy::Block`. Subscribers never lock: they read a slot
+/// optimistically and check its stamp afterwards, as a producer may have
+/// overwritten it meanwhile with `SlowReaderPolicy::Lag`.
+#[repr(C)]
+pub struct Broadcast<T, const N: usize = RING_BUFFER_SIZE>
+    where T: Copy
+{
+    /// Serializes producers, and holds the policy for slow subscribers.
+    producer: Mutex<SlowReaderPolicy>,
+    /// Sequence of the next message to publish.
+    head: CachePadded<AtomicU64>,
+    published: CachePadded<Event>,
+    consumed: CachePadded<Event>,
+    /// Sequence each subscriber reads next, or `FREE`.
+    cursors: [CachePadded<AtomicU64>; MAX_SUBSCRIBERS],
+    slots: [BroadcastSlot<T>; N]
+}
+
+unsafe impl<T: Copy + Send, const N: usize> Sync for Broadcast<T, N> {}
+
+impl<T, const N: usize> PthreadPrimitiveConstructor for Broadcast<T, N>
+    where T: Copy
+{
+    fn new() -> Self {
+        Self::with_producer(Mutex::new(SlowReaderPolicy::Lag))
+    }
+
+    fn pshared() -> Self {
+        Self::with_producer(Mutex::pshared(SlowReaderPolicy::Lag))
+    }
+}
+
+unsafe impl<T, const N: usize> SharedLayout for Broadcast<T, N>
+    where T: Copy + SharedLayout
+{
+    fn layout() -> TypeLayout {
+        TypeLayout::new::<Self>("Broadcast")
+            .field("producer", mem::offset_of!(Self, producer), |s: &Self| &s.producer)
+            .field("head", mem::offset_of!(Self, head), |s: &Self| &s.head)
+            .field("published", mem::offset_of!(Self, published), |s: &Self| &s.published)
+            .field("consumed", mem::offset_of!(Self, consumed), |s: &Self| &s.consumed)
+            .field("cursors", mem::offset_of!(Self, cursors), |s: &Self| &s.cursors)
+            .field("slots", mem::offset_of!(Self, slots), |s: &Self| &s.slots)
+    }
+}
+
+unsafe impl<T, const N: usize> Checkpoint for Broadcast<T, N>
+    where T: PlainData
+{
+    type Guard<'a> = MutexGuard<'a, SlowReaderPolicy> where T: 'a;
+
+    // Producers write slots under the lock, and subscribers only move
+    // their own cursors
+    fn freeze(&self) -> Result<MutexGuard<'_, SlowReaderPolicy>, Error> {
+        self.producer.freeze()
+    }
+
+    fn thaw(&mut self) {
+        self.producer.thaw();
+        self.published.0.thaw();
+        self.consumed.0.thaw();
+
+        // Subscribers are process-local, so none of them reach the copy
+        for cursor in self.cursors.iter_mut() {
+            *cursor.0.get_mut() = FREE;
+        }
+    }
+}
+
+#[allow(dead_code)]
+impl<T, const N: usize> Broadcast<T, N>
+    where T: Copy
+{
+    fn with_producer(producer: Mutex<SlowReaderPolicy>) -> Self {
+        assert!(N > 0, "broadcast capacity must not be zero");
+        Broadcast {
+            producer,
+            head: CachePadded(AtomicU64::new(0)),
+            published: CachePadded(Event::new()),
+            consumed: CachePadded(Event::new()),
+            cursors: [const { CachePadded(AtomicU64::new(FREE)) }; MAX_SUBSCRIBERS],
+            slots: [const { BroadcastSlot::new() }; N]
+        }
+    }
+
+    /// Sets the policy for slow subscribers, `SlowReaderPolicy::Lag` by default.
+    pub fn with_policy(mut self, policy: SlowReaderPolicy) -> Self {
+        *self.producer.get_mut() = policy;
+        self
+    }
+
+    pub fn capacity(&self) -> usize {
+        N
+    }
+
+    /// Publishes `value` to all current subscribers.
+    pub fn publish(&self, value: T) -> Result<(), Error> {
+        {
+            let policy = self.producer.lock()?;
+            let seq = self.head.0.load(Ordering::Relaxed);
+            if *policy == SlowReaderPolicy::Block {
+                self.wait_for_subscribers(seq)?;
+            }
+
+            self.slots[(seq % N as u64) as usize].write(seq, value);
+            self.head.0.store(seq + 1, Ordering::Release);
+        }
+
+        self.published.0.notify_all()
+    }
+
+    /// Number of current subscribers.
+    pub fn subscribers(&self) -> Result<usize, Error> {
+        Ok(self.cursors.iter().filter(|c| c.0.load(Ordering::Relaxed) != FREE).count())
+    }
+
+    /// Waits until no subscriber is `N` messages behind `seq`, so the
+    /// message with sequence `seq` laps nobody.
+    fn wait_for_subscribers(&self, seq: u64) -> Result<(), Error> {
+        loop {
+            let key = self.consumed.0.prepare();
+            let lapped = self.cursors.iter()
+                .map(|cursor| cursor.0.load(Ordering::Acquire))
+                .any(|cursor| cursor != FREE && seq - cursor >= N as u64);
+            if !lapped {
+                self.consumed.0.cancel();
+                return Ok(());
+            }
+            self.consumed.0.wait(key, None)?;
+        }
+    }
+}
+
+/// Subscriber of a `Broadcast`, created with `Subscriber::new`.
+/// Dropping it releases its cursor, which only the child does for a
+/// subscriber moved into `process::spawn`.
+///
+/// A subscriber in a process which dies without unwinding is never
+/// released: its cursor stays behind, and with `SlowReaderPolicy::Block`
+/// publishers block forever once they are `N` messages ahead of it.
+pub struct Subscriber<T, const N: usize = RING_BUFFER_SIZE>
+    where T: Copy
+{
+    broadcast: Shm<Broadcast<T, N>>,
+    id: usize
+}
+
+#[allow(dead_code)]
+impl<T, const N: usize> Subscriber<T, N>
+    where T: Copy
+{
+    /// Subscribes to messages published from now on.
+    /// Fails with `EUSERS` if there are `MAX_SUBSCRIBERS` already.
+    pub fn new(broadcast: &Shm<Broadcast<T, N>>) -> Result<Self, Error> {
+        // Producers check the cursors under the lock, so none of them can
+        // miss the new one and lap it
+        let _producer = broadcast.producer.lock()?;
+        let head = broadcast.head.0.load(Ordering::Relaxed);
+        let id = broadcast.cursors.iter()
+            .position(|cursor| {
+                cursor.0.compare_exchange(FREE, head, Ordering::AcqRel, Ordering::Relaxed).is_ok()
+            })
+            .ok_or(Error::Sys(Errno::EUSERS))?;
+
+        Ok(Subscriber {
+            broadcast: broadcast.clone(),
+            id
+        })
+    }
+
+    /// Receives the next message, blocking until one is published.
+    pub fn recv(&self) -> Result<T, RecvError> {
+        match self.recv_until(None)? {
+            Some(value) => Ok(value),
+            None => unreachable!("recv without deadline timed out")
+        }
+    }
+
+    pub fn try_recv(&self) -> Result<Option<T>, RecvError> {
+        self.read()
+    }
+
+    pub fn timed_recv(&self, time: Duration) -> Result<Option<T>, RecvError> {
+        self.recv_until(Some(Instant::now() + time))
+    }
+
+    /// Receives the next message, blocking until `deadline` at most.
+    pub fn recv_deadline(&self, deadline: Instant) -> Result<Option<T>, RecvError> {
+        self.recv_until(Some(deadline))
+    }
+
+    fn recv_until(&self, deadline: Option<Instant>) -> Result<Option<T>, RecvError> {
+        let published = &self.broadcast.published.0;
+        loop {
+            if let Some(value) = self.read()? {
+                return Ok(Some(value));
+            }
+
+            let key = published.prepare();
+            if let Some(value) = self.read()? {
+                published.cancel();
+                return Ok(Some(value));
+            }
+            match published.wait(key, deadline) {
+                Err(Error::Sys(Errno::ETIMEDOUT)) => return self.read(),
+                Err(err) => return Err(err.into()),
+                Ok(()) => ()
+            }
+        }
+    }
+
+    /// Reads the message at the cursor, if it was published already.
+    fn read(&self) -> Result<Option<T>, RecvError> {
+        let broadcast = &*self.broadcast;
+        let cursor = &broadcast.cursors[self.id].0;
+        loop {
+            let seq = cursor.load(Ordering::Relaxed);
+            let head = broadcast.head.0.load(Ordering::Acquire);
+            if seq == head {
+                return Ok(None);
+            }
+
+            let tail = head.saturating_sub(N as u64);
+            if seq < tail {
+                if cursor.compare_exchange(seq, tail, Ordering::AcqRel, Ordering::Relaxed).is_ok() {
+                    return Err(RecvError::Lagged(tail - seq));
+                }
+                continue;
+            }
+
+            // A producer which overwrote the slot meanwhile also moved
+            // `head` past it, so the next round reports the lag
+            let value = match broadcast.slots[(seq % N as u64) as usize].read(seq) {
+                Some(value) => value,
+                None => continue
+            };
+            if cursor.compare_exchange(seq, seq + 1, Ordering::AcqRel, Ordering::Relaxed).is_ok() {
+                broadcast.consumed.0.notify_all()?;
+                return Ok(Some(value));
+            }
+        }
+    }
+}
+
+impl<T, const N: usize> Drop for Subscriber<T, N>
+    where T: Copy
+{
+    fn drop(&mut self) {
+        if process::handing_over() {
+            return;
+        }
+        self.broadcast.cursors[self.id].0.store(FREE, Ordering::Release);
+        let _ = self.broadcast.consumed.0.notify_all();
+    }
+}
+
+/// Slot of a `Broadcast`, stamped with `2 * seq + 2` once the message with
+/// sequence `seq` was written, and with `2 * seq + 1` while it is written.
+#[repr(C)]
+struct BroadcastSlot<T>
+    where T: Copy
+{
+    stamp: AtomicU64,
+    value: UnsafeCell<MaybeUninit<T>>
+}
+
+impl<T> BroadcastSlot<T>
+    where T: Copy
+{
+    const fn new() -> Self {
+        BroadcastSlot {
+            stamp: AtomicU64::new(0),
+            value: UnsafeCell::new(MaybeUninit::uninit())
+        }
+    }
+
+    /// Writes the message `seq`. Only called under the producer lock.
+    fn write(&self, seq: u64, value: T) {
+        self.stamp.store(2 * seq + 1, Ordering::Relaxed);
+        fence(Ordering::Release);
+        unsafe {
+            ptr::write_volatile(self.value.get(), MaybeUninit::new(value));
+        }
+        self.stamp.store(2 * seq + 2, Ordering::Release);
+    }
+
+    /// Reads the message `seq`, or returns `None` if the slot was
+    /// overwritten before or during the read.
+    fn read(&self, seq: u64) -> Option<T> {
+        let stamp = self.stamp.load(Ordering::Acquire);
+        let value = unsafe { ptr::read_volatile(self.value.get()) };
+        fence(Ordering::Acquire);
+        if stamp == 2 * seq + 2 && self.stamp.load(Ordering::Relaxed) == stamp {
+            Some(unsafe { value.assume_init() })
+        } else {
+            None
+        }
+    }
+}
+
+unsafe impl<T> SharedLayout for BroadcastSlot<T>
+    where T: Copy + SharedLayout
+{
+    fn layout() -> TypeLayout {
+        TypeLayout::new::<Self>("BroadcastSlot")
+            .field("stamp", mem::offset_of!(Self, stamp), |s: &Self| &s.stamp)
+            .field("value", mem::offset_of!(Self, value), |s: &Self| &s.value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Broadcast, RecvError, SlowReaderPolicy, Subscriber, MAX_SUBSCRIBERS};
+    use ::pthread::PthreadPrimitiveConstructor;
+    use ::process;
+    use ::shm::Shm;
+    use nix::Error;
+    use nix::Errno;
+    use std::process::exit;
+    use std::time::Duration;
+
+    #[test]
+    fn fan_out() {
+        let broadcast: Shm<Broadcast<i32>> = Shm::new(Broadcast::new()).unwrap();
+        broadcast.publish(0).unwrap();
+
+        let first = Subscriber::new(&broadcast).unwrap();
+        let second = Subscriber::new(&broadcast).unwrap();
+        for i in 1..4 {
+            broadcast.publish(i).unwrap();
+        }
+
+        for i in 1..4 {
+            assert_eq!(Ok(i), first.recv());
+        }
+        assert_eq!(Ok(None), first.try_recv());
+        for i in 1..4 {
+            assert_eq!(Ok(Some(i)), second.timed_recv(Duration::from_millis(1)));
+        }
+        assert_eq!(Ok(None), second.timed_recv(Duration::from_millis(10)));
+    }
+
+    #[test]
+    fn lagged() {
+        let broadcast: Shm<Broadcast<i32, 4>> = Shm::new(Broadcast::new()).unwrap();
+        let subscriber = Subscriber::new(&broadcast).unwrap();
+
+        for i in 0..10 {
+            broadcast.publish(i).unwrap();
+        }
+
+        assert_eq!(Err(RecvError::Lagged(6)), subscriber.recv());
+        for i in 6..10 {
+            assert_eq!(Ok(i), subscriber.recv());
+        }
+        assert_eq!(Ok(None), subscriber.try_recv());
+    }
+
+    #[test]
+    fn subscriber_limit() {
+        let broadcast: Shm<Broadcast<i32>> = Shm::new(Broadcast::new()).unwrap();
+        let subscribers = (0..MAX_SUBSCRIBERS)
+            .map(|_| Subscriber::new(&broadcast).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(Err(Error::Sys(Errno::EUSERS)), Subscriber::new(&broadcast).map(|_| ()));
+
+        drop(subscribers);
+        assert_eq!(Ok(0), broadcast.subscribers());
+        assert!(Subscriber::new(&broadcast).is_ok());
+    }
+
+    #[test]
+    fn token() {
+        let broadcast: Shm<Broadcast<u32>> = Shm::new(Broadcast::pshared()).unwrap();
+        let other = Shm::<Broadcast<u32>>::from_token(&broadcast.token()).unwrap();
+        let subscriber = Subscriber::new(&other).unwrap();
+        broadcast.publish(1).unwrap();
+        assert_eq!(Ok(1), subscriber.recv());
+    }
+
+    #[test]
+    fn snapshot() {
+        let broadcast: Shm<Broadcast<i32>> = Shm::new(Broadcast::pshared()).unwrap();
+        let _subscriber = Subscriber::new(&broadcast).unwrap();
+
+        // Subscribers don't reach the copy, so their cursors are freed there
+        let snapshot = broadcast.snapshot().unwrap();
+        assert_eq!(Ok(0), snapshot.subscribers());
+        assert_eq!(Ok(1), broadcast.subscribers());
+    }
+
+    #[test]
+    fn lag_ipc() {
+        let broadcast: Shm<Broadcast<u64, 4>> = Shm::new(Broadcast::pshared()).unwrap();
+        let subscriber = Subscriber::new(&broadcast).unwrap();
+
+        {
+            let broadcast = broadcast.clone();
+            process::spawn(move || {
+                for i in 0..100000 {
+                    broadcast.publish(i).unwrap();
+                }
+            }).unwrap();
+        }
+
+        // Every message is either received in order or counted as missed
+        let mut next = 0;
+        while next < 100000 {
+            match subscriber.recv() {
+                Ok(value) => {
+                    assert_eq!(next, value);
+                    next += 1;
+                },
+                Err(RecvError::Lagged(missed)) => next += missed,
+                Err(err) => panic!("recv failed: {:?}", err)
+            }
+        }
+        assert_eq!(100000, next);
+    }
+
+    #[test]
+    fn block_ipc() {
+        let broadcast: Shm<Broadcast<i32, 4>> = Shm::new(
+            Broadcast::pshared().with_policy(SlowReaderPolicy::Block)
+        ).unwrap();
+
+        let subscribers = (0..3)
+            .map(|_| Subscriber::new(&broadcast).unwrap())
+            .collect::<Vec<_>>();
+
+        let children = subscribers.into_iter().map(|subscriber| {
+            process::spawn(move || {
+                if !(0..1000).all(|i| subscriber.recv() == Ok(i)) {
+                    exit(1);
+                }
+            }).unwrap()
+        }).collect::<Vec<_>>();
+
+        for i in 0..1000 {
+            broadcast.publish(i).unwrap();
+        }
+
+        for child in children {
+            match child.wait(None).unwrap() {
+                process::WaitStatus::Exited(_, 0) => (),
+                other => panic!("subscriber failed: {:?}", other)
+            }
+        }
+        assert_eq!(Ok(0), broadcast.subscribers());
+    }
+}
@@ -0,0 +1,329 @@
+use nix::Error;
+use nix::Errno;
+use ::pthread::PthreadPrimitiveConstructor;
+use ::pthread::PthreadWrappingPrimitiveConstructor;
+use ::pthread::Condvar;
+use ::pthread::Mutex;
+use ::pthread::MutexGuard;
+use ::shm::{Checkpoint, PlainData};
+use ::layout::{SharedLayout, TypeLayout};
+
+use std::mem;
+use std::time::{Duration, Instant};
+
+/// Default capacity of a `ByteQueue` in bytes, including frame headers.
+pub const BYTE_QUEUE_SIZE: usize = 4096;
+
+const FRAME_HEADER_SIZE: usize = mem::size_of::<u32>();
+
+/// Blocking queue of variable-length byte messages.
+///
+/// Messages are stored as frames of a `u32` length followed by the payload
+/// in a contiguous ring of `N` bytes, wrapping around its end.
+#[repr(C)]
+pub struct ByteQueue<const N: usize = BYTE_QUEUE_SIZE> {
+    buffer: Mutex<ByteRing<N>>,
+    in_cond: Condvar,
+    out_cond: Condvar
+}
+
+impl<const N: usize> PthreadPrimitiveConstructor for ByteQueue<N> {
+    fn new() -> Self {
+        ByteQueue {
+            buffer: Mutex::new(ByteRing::new()),
+            in_cond: Condvar::new(),
+            out_cond: Condvar::new(),
+        }
+    }
+
+    fn pshared() -> Self {
+        ByteQueue {
+            buffer: Mutex::pshared(ByteRing::new()),
+            in_cond: Condvar::pshared(),
+            out_cond: Condvar::pshared(),
+        }
+    }
+}
+
+unsafe impl<const N: usize> SharedLayout for ByteQueue<N> {
+    fn layout() -> TypeLayout {
+        TypeLayout::new::<Self>("ByteQueue")
+            .field("buffer", mem::offset_of!(Self, buffer), |s: &Self| &s.buffer)
+            .field("in_cond", mem::offset_of!(Self, in_cond), |s: &Self| &s.in_cond)
+            .field("out_cond", mem::offset_of!(Self, out_cond), |s: &Self| &s.out_cond)
+    }
+}
+
+unsafe impl<const N: usize> Checkpoint for ByteQueue<N> {
+    type Guard<'a> = MutexGuard<'a, ByteRing<N>>;
+
+    fn freeze(&self) -> Result<MutexGuard<'_, ByteRing<N>>, Error> {
+        self.buffer.freeze()
+    }
+
+    fn thaw(&mut self) {
+        self.buffer.thaw();
+        self.in_cond.thaw();
+        self.out_cond.thaw();
+    }
+}
+
+#[allow(dead_code)]
+impl<const N: usize> ByteQueue<N> {
+    pub fn capacity(&self) -> usize {
+        N
+    }
+
+    /// Largest message which fits into the queue.
+    pub fn max_message_size(&self) -> usize {
+        N - FRAME_HEADER_SIZE
+    }
+
+    /// Pushes `message`, blocking until there is enough space for it.
+    /// Fails with `EMSGSIZE` if it could never fit.
+    pub fn push(&self, message: &[u8]) -> Result<(), Error> {
+        self.check_size(message)?;
+
+        let mut guard = self.buffer.lock()?;
+        while !guard.write_frame(message) {
+            guard = self.out_cond.wait(guard)?;
+        }
+
+        self.in_cond.signal()
+    }
+
+    /// Pushes `message` if there is enough space for it right now.
+    pub fn try_push(&self, message: &[u8]) -> Result<bool, Error> {
+        self.check_size(message)?;
+
+        let pushed = self.buffer.lock()?.write_frame(message);
+        if pushed {
+            self.in_cond.signal()?;
+        }
+        Ok(pushed)
+    }
+
+    /// Pops a message into `message`, replacing its contents, and returns its
+    /// length. Blocks while the queue is empty.
+    pub fn pop_into(&self, message: &mut Vec<u8>) -> Result<usize, Error> {
+        let len = {
+            let mut guard = self.buffer.lock()?;
+            loop {
+                if let Some(len) = guard.read_frame(message) {
+                    break len;
+                }
+                guard = self.in_cond.wait(guard)?;
+            }
+        };
+
+        self.out_cond.broadcast()?;
+        Ok(len)
+    }
+
+    pub fn try_pop_into(&self, message: &mut Vec<u8>) -> Result<Option<usize>, Error> {
+        let len = self.buffer.lock()?.read_frame(message);
+        if len.is_some() {
+            self.out_cond.broadcast()?;
+        }
+        Ok(len)
+    }
+
+    pub fn timed_pop_into(&self, message: &mut Vec<u8>, time: Duration) -> Result<Option<usize>, Error> {
+        self.pop_into_deadline(message, Instant::now() + time)
+    }
+
+    /// Like `pop_into`, but blocks until `deadline` at most.
+    pub fn pop_into_deadline(&self, message: &mut Vec<u8>, deadline: Instant) -> Result<Option<usize>, Error> {
+        let len = {
+            let mut guard = self.buffer.lock()?;
+            loop {
+                if let Some(len) = guard.read_frame(message) {
+                    break Some(len);
+                }
+                if Instant::now() >= deadline {
+                    break None;
+                }
+                guard = match self.in_cond.wait_until(guard, deadline) {
+                    Err(Error::Sys(Errno::ETIMEDOUT)) => self.buffer.lock()?,
+                    Err(err) => return Err(err),
+                    Ok(guard) => guard
+                };
+            }
+        };
+
+        if len.is_some() {
+            self.out_cond.broadcast()?;
+        }
+        Ok(len)
+    }
+
+    fn check_size(&self, message: &[u8]) -> Result<(), Error> {
+        if message.len() > self.max_message_size() || message.len() > u32::MAX as usize {
+            Err(Error::Sys(Errno::EMSGSIZE))
+        } else {
+            Ok(())
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug)]
+pub struct ByteRing<const N: usize> {
+    read_idx: usize,
+    len: usize,
+    bytes: [u8; N]
+}
+
+unsafe impl<const N: usize> PlainData for ByteRing<N> {}
+
+unsafe impl<const N: usize> SharedLayout for ByteRing<N> {
+    fn layout() -> TypeLayout {
+        TypeLayout::new::<Self>("ByteRing")
+            .field("read_idx", mem::offset_of!(Self, read_idx), |s: &Self| &s.read_idx)
+            .field("len", mem::offset_of!(Self, len), |s: &Self| &s.len)
+            .field("bytes", mem::offset_of!(Self, bytes), |s: &Self| &s.bytes)
+    }
+}
+
+impl<const N: usize> ByteRing<N> {
+    pub fn new() -> Self {
+        assert!(N > FRAME_HEADER_SIZE, "byte queue must fit a frame header");
+        ByteRing {
+            read_idx: 0,
+            len: 0,
+            bytes: [0; N]
+        }
+    }
+
+    pub fn write_frame(&mut self, message: &[u8]) -> bool {
+        if N - self.len < FRAME_HEADER_SIZE + message.len() {
+            return false;
+        }
+
+        self.write(&(message.len() as u32).to_le_bytes());
+        self.write(message);
+        true
+    }
+
+    pub fn read_frame(&mut self, message: &mut Vec<u8>) -> Option<usize> {
+        if self.len == 0 {
+            return None;
+        }
+
+        let mut header = [0; FRAME_HEADER_SIZE];
+        self.read(&mut header);
+        let len = u32::from_le_bytes(header) as usize;
+
+        message.clear();
+        message.resize(len, 0);
+        self.read(message);
+        Some(len)
+    }
+
+    fn write(&mut self, data: &[u8]) {
+        let start = (self.read_idx + self.len) % N;
+        let first = data.len().min(N - start);
+        self.bytes[start..start + first].copy_from_slice(&data[..first]);
+        self.bytes[..data.len() - first].copy_from_slice(&data[first..]);
+        self.len += data.len();
+    }
+
+    fn read(&mut self, data: &mut [u8]) {
+        let first = data.len().min(N - self.read_idx);
+        let rest = data.len() - first;
+        data[..first].copy_from_slice(&self.bytes[self.read_idx..self.read_idx + first]);
+        data[first..].copy_from_slice(&self.bytes[..rest]);
+        self.read_idx = (self.read_idx + data.len()) % N;
+        self.len -= data.len();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    mod byte_ring {
+        use super::super::ByteRing;
+
+        #[test]
+        fn rw() {
+            let mut ring: ByteRing<16> = ByteRing::new();
+            let mut message = Vec::new();
+            assert_eq!(None, ring.read_frame(&mut message));
+            assert!(ring.write_frame(b"hello"));
+            assert!(ring.write_frame(b""));
+            assert_eq!(Some(5), ring.read_frame(&mut message));
+            assert_eq!(b"hello", &message[..]);
+            assert_eq!(Some(0), ring.read_frame(&mut message));
+            assert!(message.is_empty());
+            assert_eq!(None, ring.read_frame(&mut message));
+        }
+
+        #[test]
+        fn overflow() {
+            let mut ring: ByteRing<16> = ByteRing::new();
+            assert!(ring.write_frame(b"12345678"));
+            assert!(!ring.write_frame(b"1234"));
+            assert!(ring.write_frame(b""));
+            assert!(!ring.write_frame(b""));
+        }
+
+        #[test]
+        fn wraparound() {
+            let mut ring: ByteRing<16> = ByteRing::new();
+            let mut message = Vec::new();
+            for i in 0..100u8 {
+                let sent = vec![i; (i % 9) as usize];
+                assert!(ring.write_frame(&sent));
+                assert_eq!(Some(sent.len()), ring.read_frame(&mut message));
+                assert_eq!(sent, message);
+            }
+        }
+    }
+
+    mod byte_queue {
+        use super::super::ByteQueue;
+        use ::pthread::PthreadPrimitiveConstructor;
+        use ::process;
+        use ::shm::Shm;
+        use nix::Error;
+        use nix::Errno;
+        use std::time::Duration;
+
+        #[test]
+        fn message_size() {
+            let queue: ByteQueue<16> = ByteQueue::new();
+            assert_eq!(Err(Error::Sys(Errno::EMSGSIZE)), queue.push(&[0; 13]));
+            assert_eq!(Ok(()), queue.push(&[0; 12]));
+            assert_eq!(Ok(false), queue.try_push(&[]));
+        }
+
+        #[test]
+        fn timed_pop_into() {
+            let queue: ByteQueue = ByteQueue::new();
+            let mut message = Vec::new();
+            assert_eq!(Ok(None), queue.timed_pop_into(&mut message, Duration::from_millis(10)));
+            queue.push(b"json").unwrap();
+            assert_eq!(Ok(Some(4)), queue.timed_pop_into(&mut message, Duration::from_millis(10)));
+            assert_eq!(b"json", &message[..]);
+        }
+
+        #[test]
+        fn ipc() {
+            let queue: Shm<ByteQueue<64>> = Shm::new(ByteQueue::pshared()).unwrap();
+
+            {
+                let queue = queue.clone();
+                process::spawn(move || {
+                    for i in 0..1000 {
+                        queue.push(format!("message {}", i).as_bytes()).unwrap();
+                    }
+                }).unwrap();
+            }
+
+            let mut message = Vec::new();
+            for i in 0..1000 {
+                queue.pop_into(&mut message).unwrap();
+                assert_eq!(format!("message {}", i).as_bytes(), &message[..]);
+            }
+        }
+    }
+}
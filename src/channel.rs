@@ -0,0 +1,181 @@
+use bincode;
+use nix;
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use ::byte_queue::{ByteQueue, BYTE_QUEUE_SIZE};
+use ::pthread::PthreadPrimitiveConstructor;
+
+use std::error;
+use std::fmt;
+use std::marker::PhantomData;
+use std::time::{Duration, Instant};
+
+#[derive(Debug)]
+pub enum ChannelError {
+    Sys(nix::Error),
+    Codec(bincode::Error)
+}
+
+impl From<nix::Error> for ChannelError {
+    fn from(err: nix::Error) -> Self {
+        ChannelError::Sys(err)
+    }
+}
+
+impl From<bincode::Error> for ChannelError {
+    fn from(err: bincode::Error) -> Self {
+        ChannelError::Codec(err)
+    }
+}
+
+impl fmt::Display for ChannelError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ChannelError::Sys(ref err) => write!(f, "{:?}", err),
+            ChannelError::Codec(ref err) => write!(f, "codec error: {}", err)
+        }
+    }
+}
+
+impl error::Error for ChannelError {}
+
+/// Blocking channel for any serde-serializable messages, encoded with
+/// bincode into a `ByteQueue` of `N` bytes.
+pub struct TypedChannel<T, const N: usize = BYTE_QUEUE_SIZE> {
+    queue: ByteQueue<N>,
+    _message: PhantomData<fn(T) -> T>
+}
+
+impl<T, const N: usize> PthreadPrimitiveConstructor for TypedChannel<T, N> {
+    fn new() -> Self {
+        TypedChannel {
+            queue: ByteQueue::new(),
+            _message: PhantomData
+        }
+    }
+
+    fn pshared() -> Self {
+        TypedChannel {
+            queue: ByteQueue::pshared(),
+            _message: PhantomData
+        }
+    }
+}
+
+#[allow(dead_code)]
+impl<T, const N: usize> TypedChannel<T, N>
+    where T: Serialize + DeserializeOwned
+{
+    /// Pushes `value`, blocking until there is enough space for its encoding.
+    /// Fails with `EMSGSIZE` if the encoding could never fit.
+    pub fn push(&self, value: T) -> Result<(), ChannelError> {
+        let message = bincode::serialize(&value)?;
+        Ok(self.queue.push(&message)?)
+    }
+
+    pub fn pop(&self) -> Result<T, ChannelError> {
+        let mut message = Vec::new();
+        self.queue.pop_into(&mut message)?;
+        Ok(bincode::deserialize(&message)?)
+    }
+
+    pub fn try_pop(&self) -> Result<Option<T>, ChannelError> {
+        let mut message = Vec::new();
+        match self.queue.try_pop_into(&mut message)? {
+            Some(_) => Ok(Some(bincode::deserialize(&message)?)),
+            None => Ok(None)
+        }
+    }
+
+    pub fn timed_pop(&self, time: Duration) -> Result<Option<T>, ChannelError> {
+        self.pop_deadline(Instant::now() + time)
+    }
+
+    pub fn pop_deadline(&self, deadline: Instant) -> Result<Option<T>, ChannelError> {
+        let mut message = Vec::new();
+        match self.queue.pop_into_deadline(&mut message, deadline)? {
+            Some(_) => Ok(Some(bincode::deserialize(&message)?)),
+            None => Ok(None)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ChannelError, TypedChannel};
+    use ::pthread::PthreadPrimitiveConstructor;
+    use ::process;
+    use ::shm::Shm;
+    use nix::Error;
+    use nix::Errno;
+    use std::collections::HashMap;
+    use std::time::Duration;
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Message {
+        from: String,
+        tags: Vec<String>,
+        values: HashMap<u32, f64>
+    }
+
+    fn message(i: u32) -> Message {
+        Message {
+            from: format!("producer {}", i),
+            tags: (0..i % 5).map(|t| t.to_string()).collect(),
+            values: (0..i % 3).map(|k| (k, k as f64 / 2.0)).collect()
+        }
+    }
+
+    #[test]
+    fn try_pop() {
+        let channel: TypedChannel<Message> = TypedChannel::new();
+        assert!(channel.try_pop().unwrap().is_none());
+        channel.push(message(7)).unwrap();
+        assert_eq!(Some(message(7)), channel.try_pop().unwrap());
+    }
+
+    #[test]
+    fn timed_pop() {
+        let channel: TypedChannel<String> = TypedChannel::new();
+        assert!(channel.timed_pop(Duration::from_millis(10)).unwrap().is_none());
+        channel.push("json".to_owned()).unwrap();
+        assert_eq!(Some("json".to_owned()), channel.timed_pop(Duration::from_millis(10)).unwrap());
+    }
+
+    #[test]
+    fn message_size() {
+        let channel: TypedChannel<Vec<u8>, 64> = TypedChannel::new();
+        match channel.push(vec![0; 64]) {
+            Err(ChannelError::Sys(Error::Sys(Errno::EMSGSIZE))) => (),
+            other => panic!("expected EMSGSIZE, got {:?}", other)
+        }
+    }
+
+    #[test]
+    fn codec_error() {
+        let channel: TypedChannel<String> = TypedChannel::new();
+        channel.queue.push(&[0xff; 16]).unwrap();
+        match channel.pop() {
+            Err(ChannelError::Codec(_)) => (),
+            other => panic!("expected codec error, got {:?}", other)
+        }
+    }
+
+    #[test]
+    fn ipc() {
+        let channel: Shm<TypedChannel<Message, 256>> = Shm::new(TypedChannel::pshared()).unwrap();
+
+        {
+            let channel = channel.clone();
+            process::spawn(move || {
+                for i in 0..100 {
+                    channel.push(message(i)).unwrap();
+                }
+            }).unwrap();
+        }
+
+        for i in 0..100 {
+            assert_eq!(message(i), channel.pop().unwrap());
+        }
+    }
+}
@@ -0,0 +1,206 @@
+use nix::Result;
+use nix::Error;
+use nix::Errno;
+use nix::libc;
+use ::pthread::PthreadPrimitiveConstructor;
+use ::shm::Checkpoint;
+
+use std::ptr;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::time::{Duration, Instant};
+
+/// Blocks while `word` holds `expected`, for at most `timeout`.
+///
+/// Returns early on wakeups, signals and if `word` does not hold `expected`,
+/// so callers must re-check their condition. Fails with `ETIMEDOUT`.
+pub fn wait(word: &AtomicU32, expected: u32, timeout: Option<Duration>) -> Result<()> {
+    let timeout = timeout.map(|timeout| libc::timespec {
+        tv_sec: timeout.as_secs() as libc::time_t,
+        tv_nsec: timeout.subsec_nanos() as libc::c_long
+    });
+
+    let status = unsafe {
+        libc::syscall(
+            libc::SYS_futex,
+            word.as_ptr(),
+            libc::FUTEX_WAIT,
+            expected,
+            timeout.as_ref().map_or(ptr::null(), |timeout| timeout as *const _),
+            ptr::null::<u32>(),
+            0
+        )
+    };
+
+    if status == -1 {
+        match Errno::last() {
+            Errno::EAGAIN | Errno::EINTR => Ok(()),
+            errno => Err(Error::Sys(errno))
+        }
+    } else {
+        Ok(())
+    }
+}
+
+/// Wakes up to `count` processes blocked in `wait` on `word`.
+pub fn wake(word: &AtomicU32, count: u32) -> Result<()> {
+    let status = unsafe {
+        libc::syscall(
+            libc::SYS_futex,
+            word.as_ptr(),
+            libc::FUTEX_WAKE,
+            count.min(i32::MAX as u32)
+        )
+    };
+
+    if status == -1 {
+        Err(Error::Sys(Errno::last()))
+    } else {
+        Ok(())
+    }
+}
+
+/// Wait/notify primitive for lock-free structures, living in shared memory.
+///
+/// A waiter calls `prepare`, re-checks its condition and then either calls
+/// `wait` with the returned key or `cancel`. Notifications only enter the
+/// kernel when somebody is actually waiting.
+#[repr(C)]
+pub struct Event {
+    seq: AtomicU32,
+    waiters: AtomicU32
+}
+
+shared_layout!(Event { seq, waiters });
+
+impl Event {
+    pub fn new() -> Self {
+        Event {
+            seq: AtomicU32::new(0),
+            waiters: AtomicU32::new(0)
+        }
+    }
+
+    pub fn prepare(&self) -> u32 {
+        self.waiters.fetch_add(1, Ordering::SeqCst);
+        self.seq.load(Ordering::SeqCst)
+    }
+
+    pub fn cancel(&self) {
+        self.waiters.fetch_sub(1, Ordering::SeqCst);
+    }
+
+    /// Blocks until notified after `prepare` returned `key`, or until
+    /// `deadline`. Fails with `ETIMEDOUT`.
+    pub fn wait(&self, key: u32, deadline: Option<Instant>) -> Result<()> {
+        let timeout = match deadline {
+            Some(deadline) => match deadline.checked_duration_since(Instant::now()) {
+                Some(timeout) => Some(timeout),
+                None => {
+                    self.cancel();
+                    return Err(Error::Sys(Errno::ETIMEDOUT));
+                }
+            },
+            None => None
+        };
+
+        let result = wait(&self.seq, key, timeout);
+        self.cancel();
+        result
+    }
+
+    pub fn notify_one(&self) -> Result<()> {
+        self.seq.fetch_add(1, Ordering::SeqCst);
+        if self.waiters.load(Ordering::SeqCst) != 0 {
+            wake(&self.seq, 1)
+        } else {
+            Ok(())
+        }
+    }
+
+    pub fn notify_all(&self) -> Result<()> {
+        self.seq.fetch_add(1, Ordering::SeqCst);
+        if self.waiters.load(Ordering::SeqCst) != 0 {
+            wake(&self.seq, u32::MAX)
+        } else {
+            Ok(())
+        }
+    }
+}
+
+// Futexes are process-shared as long as they live in shared memory
+impl PthreadPrimitiveConstructor for Event {
+    fn new() -> Self {
+        Event::new()
+    }
+
+    fn pshared() -> Self {
+        Event::new()
+    }
+}
+
+unsafe impl Checkpoint for Event {
+    type Guard<'a> = ();
+
+    fn freeze(&self) -> Result<()> {
+        Ok(())
+    }
+
+    // Nobody waits on a restored event
+    fn thaw(&mut self) {
+        *self.waiters.get_mut() = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Event;
+    use nix::Error;
+    use nix::Errno;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn timeout() {
+        let event = Event::new();
+        let key = event.prepare();
+        let deadline = Instant::now() + Duration::from_millis(10);
+        assert_eq!(Err(Error::Sys(Errno::ETIMEDOUT)), event.wait(key, Some(deadline)));
+        assert!(Instant::now() >= deadline);
+    }
+
+    #[test]
+    fn stale_key() {
+        let event = Event::new();
+        let key = event.prepare();
+        event.notify_all().unwrap();
+        assert_eq!(Ok(()), event.wait(key, None));
+    }
+
+    #[test]
+    fn notify() {
+        let event = Arc::new(Event::new());
+        let flag = Arc::new(AtomicBool::new(false));
+
+        let waiter = {
+            let event = event.clone();
+            let flag = flag.clone();
+            thread::spawn(move || {
+                while !flag.load(Ordering::SeqCst) {
+                    let key = event.prepare();
+                    if flag.load(Ordering::SeqCst) {
+                        event.cancel();
+                        break;
+                    }
+                    event.wait(key, None).unwrap();
+                }
+            })
+        };
+
+        thread::sleep(Duration::from_millis(10));
+        flag.store(true, Ordering::SeqCst);
+        event.notify_all().unwrap();
+        waiter.join().unwrap();
+    }
+}
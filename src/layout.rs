@@ -0,0 +1,444 @@
+use std::borrow::Cow;
+use std::cell::UnsafeCell;
+use std::fmt;
+use std::mem;
+use std::mem::MaybeUninit;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU32, AtomicU64, AtomicUsize};
+use ::shm::PlainData;
+
+/// Field of a `TypeLayout`: its name, offset in the parent and own layout.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct FieldLayout {
+    pub name: Cow<'static, str>,
+    pub offset: usize,
+    pub layout: TypeLayout
+}
+
+/// Runtime description of the in-memory layout of a type shared between
+/// processes, which may be built for different architectures.
+///
+/// Layouts are handed to other programs in a textual form, e.g.
+/// `Point(8,4){x@0:u8(1,1),y@4:u32(4,4)}`, see `Display` and `FromStr`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct TypeLayout {
+    pub name: Cow<'static, str>,
+    pub size: usize,
+    pub align: usize,
+    pub fields: Vec<FieldLayout>
+}
+
+#[allow(dead_code)]
+impl TypeLayout {
+    /// Layout of `T` without fields, e.g. for primitives and opaque types.
+    pub fn new<T>(name: &'static str) -> Self {
+        TypeLayout {
+            name: Cow::Borrowed(name),
+            size: mem::size_of::<T>(),
+            align: mem::align_of::<T>(),
+            fields: Vec::new()
+        }
+    }
+
+    /// Adds field `name` at `offset`. `field` is only used to infer the
+    /// field's type, e.g. `|queue: &Self| &queue.buffer`.
+    pub fn field<S, F>(mut self, name: &'static str, offset: usize, _field: fn(&S) -> &F) -> Self
+        where F: SharedLayout
+    {
+        self.fields.push(FieldLayout {
+            name: Cow::Borrowed(name),
+            offset,
+            layout: F::layout()
+        });
+        self
+    }
+
+    /// Hash of the layout and of the endianness.
+    pub fn digest(&self) -> u64 {
+        // FNV-1a
+        let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+        {
+            let mut feed = |bytes: &[u8]| {
+                for byte in bytes {
+                    hash ^= *byte as u64;
+                    hash = hash.wrapping_mul(0x0100_0000_01b3);
+                }
+            };
+            feed(&[cfg!(target_endian = "little") as u8]);
+            self.feed_digest(&mut feed);
+        }
+        hash
+    }
+
+    fn feed_digest<F: FnMut(&[u8])>(&self, feed: &mut F) {
+        feed(self.name.as_bytes());
+        feed(&(self.size as u64).to_le_bytes());
+        feed(&(self.align as u64).to_le_bytes());
+        for field in &self.fields {
+            feed(field.name.as_bytes());
+            feed(&(field.offset as u64).to_le_bytes());
+            field.layout.feed_digest(feed);
+        }
+    }
+
+    /// Compares `self` to the `expected` layout and reports the first
+    /// difference.
+    pub fn check(&self, expected: &TypeLayout) -> Result<(), LayoutMismatch> {
+        self.check_at(expected, expected.name.clone().into_owned())
+    }
+
+    fn check_at(&self, expected: &TypeLayout, path: String) -> Result<(), LayoutMismatch> {
+        if self.name != expected.name {
+            return Err(LayoutMismatch::new(path, "type", &expected.name, &self.name));
+        }
+        if self.size != expected.size {
+            return Err(LayoutMismatch::new(path, "size", expected.size, self.size));
+        }
+        if self.align != expected.align {
+            return Err(LayoutMismatch::new(path, "align", expected.align, self.align));
+        }
+        if self.fields.len() != expected.fields.len() {
+            return Err(LayoutMismatch::new(path, "field count", expected.fields.len(), self.fields.len()));
+        }
+
+        for (field, expected) in self.fields.iter().zip(&expected.fields) {
+            let path = format!("{}.{}", path, expected.name);
+            if field.name != expected.name {
+                return Err(LayoutMismatch::new(path, "field", &expected.name, &field.name));
+            }
+            if field.offset != expected.offset {
+                return Err(LayoutMismatch::new(path, "offset", expected.offset, field.offset));
+            }
+            field.layout.check_at(&expected.layout, path)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl fmt::Display for TypeLayout {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}({},{})", self.name, self.size, self.align)?;
+        if !self.fields.is_empty() {
+            for (i, field) in self.fields.iter().enumerate() {
+                let separator = if i == 0 { '{' } else { ',' };
+                write!(f, "{}{}@{}:{}", separator, field.name, field.offset, field.layout)?;
+            }
+            write!(f, "}}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Error parsing the textual form of a `TypeLayout`.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct ParseLayoutError;
+
+impl FromStr for TypeLayout {
+    type Err = ParseLayoutError;
+
+    fn from_str(s: &str) -> Result<Self, ParseLayoutError> {
+        let mut parser = LayoutParser(s);
+        let layout = parser.layout()?;
+        if parser.0.is_empty() {
+            Ok(layout)
+        } else {
+            Err(ParseLayoutError)
+        }
+    }
+}
+
+/// Recursive descent parser over the unparsed rest of a layout.
+struct LayoutParser<'a>(&'a str);
+
+impl<'a> LayoutParser<'a> {
+    fn layout(&mut self) -> Result<TypeLayout, ParseLayoutError> {
+        let name = self.until('(')?;
+        let size = self.number_until(',')?;
+        let align = self.number_until(')')?;
+
+        let mut fields = Vec::new();
+        if self.eat('{') {
+            loop {
+                let name = self.until('@')?;
+                let offset = self.number_until(':')?;
+                fields.push(FieldLayout {
+                    name: Cow::Owned(name.to_owned()),
+                    offset,
+                    layout: self.layout()?
+                });
+                if self.eat('}') {
+                    break;
+                }
+                if !self.eat(',') {
+                    return Err(ParseLayoutError);
+                }
+            }
+        }
+
+        Ok(TypeLayout { name: Cow::Owned(name.to_owned()), size, align, fields })
+    }
+
+    /// Consumes everything up to and including `end`, returning what's before.
+    fn until(&mut self, end: char) -> Result<&'a str, ParseLayoutError> {
+        let idx = self.0.find(end).ok_or(ParseLayoutError)?;
+        let token = &self.0[..idx];
+        self.0 = &self.0[idx + end.len_utf8()..];
+        Ok(token)
+    }
+
+    fn number_until(&mut self, end: char) -> Result<usize, ParseLayoutError> {
+        self.until(end)?.parse().map_err(|_| ParseLayoutError)
+    }
+
+    fn eat(&mut self, c: char) -> bool {
+        match self.0.strip_prefix(c) {
+            Some(rest) => {
+                self.0 = rest;
+                true
+            }
+            None => false
+        }
+    }
+}
+
+/// First difference between two `TypeLayout`s: the expected and found
+/// type name, field name, size, alignment, field count or offset.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct LayoutMismatch {
+    pub path: String,
+    pub what: &'static str,
+    pub expected: String,
+    pub found: String
+}
+
+impl LayoutMismatch {
+    fn new<V: fmt::Display>(path: String, what: &'static str, expected: V, found: V) -> Self {
+        LayoutMismatch { path, what, expected: expected.to_string(), found: found.to_string() }
+    }
+}
+
+impl fmt::Display for LayoutMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "layout mismatch at `{}`: {} differs (expected {}, found {})",
+               self.path, self.what, self.expected, self.found)
+    }
+}
+
+/// Types with a fixed layout which may be attached to by other programs.
+///
+/// # Safety
+///
+/// Implementors must be `#[repr(C)]` (or primitives) and describe every
+/// field in `layout`.
+pub unsafe trait SharedLayout {
+    fn layout() -> TypeLayout;
+}
+
+/// Implements `SharedLayout` for a non-generic `#[repr(C)]` struct.
+///
+/// ```ignore
+/// shared_layout!(Point { x, y });
+/// ```
+macro_rules! shared_layout {
+    ($ty:ident { $($field:ident),* $(,)* }) => {
+        unsafe impl $crate::layout::SharedLayout for $ty {
+            fn layout() -> $crate::layout::TypeLayout {
+                $crate::layout::TypeLayout::new::<$ty>(stringify!($ty))
+                    $(.field(stringify!($field),
+                             ::std::mem::offset_of!($ty, $field),
+                             |s: &$ty| &s.$field))*
+            }
+        }
+    }
+}
+
+macro_rules! primitive_layout {
+    ($($ty:ty),*) => {
+        $(unsafe impl SharedLayout for $ty {
+            fn layout() -> TypeLayout {
+                TypeLayout::new::<$ty>(stringify!($ty))
+            }
+        })*
+    }
+}
+
+primitive_layout!(u8, u16, u32, u64, usize, i8, i16, i32, i64, isize, f32, f64, bool, char);
+primitive_layout!(AtomicU32, AtomicU64, AtomicUsize);
+
+unsafe impl<T: SharedLayout, const N: usize> SharedLayout for [T; N] {
+    fn layout() -> TypeLayout {
+        TypeLayout::new::<Self>("array")
+            .field("item", 0, |s: &Self| &s[0])
+    }
+}
+
+unsafe impl<T: SharedLayout> SharedLayout for UnsafeCell<T> {
+    fn layout() -> TypeLayout {
+        TypeLayout::new::<Self>("UnsafeCell")
+            .field("value", 0, |s: &Self| unsafe { &*s.get() })
+    }
+}
+
+unsafe impl<T: SharedLayout> SharedLayout for MaybeUninit<T> {
+    fn layout() -> TypeLayout {
+        TypeLayout::new::<Self>("MaybeUninit")
+            .field("value", 0, |s: &Self| unsafe { s.assume_init_ref() })
+    }
+}
+
+/// `Option` with a `#[repr(C)]` layout, for optional values in shared
+/// memory. The layout of `Option` itself is up to the compiler.
+#[repr(C)]
+#[derive(Copy, Clone)]
+pub struct SharedOption<T: Copy> {
+    is_some: bool,
+    value: MaybeUninit<T>
+}
+
+#[allow(dead_code)]
+impl<T: Copy> SharedOption<T> {
+    pub const fn none() -> Self {
+        SharedOption { is_some: false, value: MaybeUninit::uninit() }
+    }
+
+    pub const fn some(value: T) -> Self {
+        SharedOption { is_some: true, value: MaybeUninit::new(value) }
+    }
+
+    pub fn is_some(&self) -> bool {
+        self.is_some
+    }
+
+    pub fn is_none(&self) -> bool {
+        !self.is_some
+    }
+
+    pub fn as_ref(&self) -> Option<&T> {
+        if self.is_some {
+            Some(unsafe { self.value.assume_init_ref() })
+        } else {
+            None
+        }
+    }
+
+    pub fn take(&mut self) -> Option<T> {
+        let value = self.as_ref().copied();
+        self.is_some = false;
+        value
+    }
+}
+
+impl<T: Copy + fmt::Debug> fmt::Debug for SharedOption<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.as_ref().fmt(f)
+    }
+}
+
+unsafe impl<T: Copy + SharedLayout> SharedLayout for SharedOption<T> {
+    fn layout() -> TypeLayout {
+        TypeLayout::new::<Self>("SharedOption")
+            .field("is_some", mem::offset_of!(Self, is_some), |s: &Self| &s.is_some)
+            .field("value", mem::offset_of!(Self, value), |s: &Self| &s.value)
+    }
+}
+
+unsafe impl<T: PlainData> PlainData for SharedOption<T> {}
+
+#[cfg(test)]
+mod tests {
+    use super::{SharedLayout, SharedOption, TypeLayout};
+
+    #[repr(C)]
+    #[derive(Copy, Clone)]
+    struct Point {
+        x: u8,
+        y: u32
+    }
+
+    shared_layout!(Point { x, y });
+
+    #[test]
+    fn derive() {
+        let layout = Point::layout();
+        assert_eq!(8, layout.size);
+        assert_eq!(vec!["x", "y"], layout.fields.iter().map(|f| &f.name[..]).collect::<Vec<_>>());
+        assert_eq!(vec![0, 4], layout.fields.iter().map(|f| f.offset).collect::<Vec<_>>());
+        assert_eq!(Ok(()), layout.check(&Point::layout()));
+        assert_eq!(layout.digest(), Point::layout().digest());
+    }
+
+    #[test]
+    fn text() {
+        let layout = Point::layout();
+        assert_eq!("Point(8,4){x@0:u8(1,1),y@4:u32(4,4)}", layout.to_string());
+        assert_eq!(Ok(layout), "Point(8,4){x@0:u8(1,1),y@4:u32(4,4)}".parse());
+
+        let nested = <[SharedOption<Point>; 2]>::layout();
+        assert_eq!(Ok(nested.clone()), nested.to_string().parse());
+
+        for invalid in &["", "Point(8)", "Point(8,4){}", "Point(8,4){x@0:u8(1,1)", "u8(1,1)x"] {
+            assert!(invalid.parse::<TypeLayout>().is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn shared_option() {
+        let mut value = SharedOption::some(1u32);
+        assert_eq!(Some(&1), value.as_ref());
+        assert_eq!(Some(1), value.take());
+        assert!(value.is_none());
+        assert_eq!(None, value.take());
+        assert_eq!(vec![0, 4], SharedOption::<u32>::layout().fields.iter().map(|f| f.offset).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn mismatch() {
+        let native = Point::layout();
+
+        // Same struct as laid out by a build with 2-byte aligned u32
+        let mut packed = native.clone();
+        packed.size = 6;
+        packed.fields[1].offset = 2;
+        packed.fields[1].layout.align = 2;
+
+        let err = packed.check(&native).unwrap_err();
+        assert_eq!("Point", err.path);
+        assert_eq!("size", err.what);
+        assert_eq!("layout mismatch at `Point`: size differs (expected 8, found 6)", err.to_string());
+        assert!(packed.digest() != native.digest());
+
+        packed.size = 8;
+        let err = packed.check(&native).unwrap_err();
+        assert_eq!(("Point.y", "offset", "4", "2"), (&err.path[..], err.what, &err.expected[..], &err.found[..]));
+
+        let mut renamed = native.clone();
+        renamed.name = "Pixel".into();
+        renamed.fields[0].name = "col".into();
+        let err = renamed.check(&native).unwrap_err();
+        assert_eq!("layout mismatch at `Point`: type differs (expected Point, found Pixel)", err.to_string());
+
+        renamed.name = "Point".into();
+        let err = renamed.check(&native).unwrap_err();
+        assert_eq!(("Point.x", "field", "x", "col"), (&err.path[..], err.what, &err.expected[..], &err.found[..]));
+    }
+
+    #[test]
+    fn usize_width() {
+        let native = <[usize; 2]>::layout();
+
+        // `[usize; 2]` of a 32-bit build
+        let narrow = TypeLayout {
+            size: 8,
+            align: 4,
+            fields: vec![super::FieldLayout {
+                name: "item".into(),
+                offset: 0,
+                layout: TypeLayout { size: 4, align: 4, ..usize::layout() }
+            }],
+            ..native.clone()
+        };
+
+        assert_eq!(narrow.check(&native).is_ok(), cfg!(target_pointer_width = "32"));
+    }
+}
@@ -1,19 +1,36 @@
 extern crate nix;
 extern crate rand;
-
+extern crate serde;
+extern crate bincode;
+#[cfg(feature = "async")]
+extern crate tokio;
+#[cfg(test)]
+#[macro_use]
+extern crate serde_derive;
+
+#[macro_use]
+mod layout;
 mod shm;
 mod queue;
 mod process;
 mod pthread;
-
-use shm::Shm;
-use queue::Queue;
-use pthread::PthreadPrimitiveConstructor;
+mod managed;
+mod futex;
+mod spsc;
+mod mpmc;
+mod byte_queue;
+mod channel;
+mod broadcast;
+mod priority;
+mod select;
+mod rpc;
+#[cfg(feature = "async")]
+mod async_endpoints;
+
+use queue::{ipc_queue, Sender};
 use rand::{SeedableRng, StdRng, Rng};
 
-use std::time::Duration;
-
-fn producer(queue: Shm<Queue<(i32, u32)>>) {
+fn producer(queue: Sender<(i32, u32)>) {
     let pid = process::pid();
     let mut rnd: StdRng = SeedableRng::from_seed(&[pid as usize][..]);
 
@@ -23,17 +40,21 @@ fn producer(queue: Shm<Queue<(i32, u32)>>) {
 
 fn main() {    
     // Create new queue in shm
-    let queue = Shm::new(Queue::pshared())
+    let (tx, rx) = ipc_queue()
         .unwrap();
 
     // Spawn random numbers producers
     for _ in 0..10 {
-        process::spawn(|| {
-            producer(queue.clone());
+        let tx = tx.clone();
+        process::spawn(move || {
+            producer(tx);
         }).unwrap();    
     }
+    drop(tx);
 
-    while let Ok(Some(msg)) = queue.timed_pop(Duration::from_millis(10)) {
+    // Runs until all producers exited
+    for msg in rx {
+        let msg = msg.unwrap();
         println!("PID {}: {}", msg.0, msg.1);
     }   
-}
\ No newline at end of file
+}
@@ -0,0 +1,262 @@
+use nix::Result;
+use nix::Error;
+use nix::Errno;
+use ::pthread::PthreadPrimitiveConstructor;
+use ::pthread::PthreadWrappingPrimitiveConstructor;
+use ::pthread::Mutex;
+use ::layout::{SharedLayout, SharedOption, TypeLayout};
+
+use std::cell::UnsafeCell;
+use std::mem;
+use std::ptr;
+
+const NAME_LEN: usize = 32;
+const DIRECTORY_SIZE: usize = 32;
+const ARENA_ALIGN: usize = 64;
+pub const ARENA_SIZE: usize = 64 * 1024;
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug)]
+struct Entry {
+    name: [u8; NAME_LEN],
+    name_len: usize,
+    offset: usize,
+    /// Digest of the `TypeLayout` of the object's type.
+    layout: u64
+}
+
+shared_layout!(Entry { name, name_len, offset, layout });
+
+impl Entry {
+    fn name(&self) -> &[u8] {
+        &self.name[..self.name_len]
+    }
+}
+
+#[repr(C)]
+#[derive(Debug)]
+struct Directory {
+    entries: [SharedOption<Entry>; DIRECTORY_SIZE],
+    allocated: usize
+}
+
+shared_layout!(Directory { entries, allocated });
+
+impl Directory {
+    fn new() -> Self {
+        Directory {
+            entries: [SharedOption::none(); DIRECTORY_SIZE],
+            allocated: 0
+        }
+    }
+
+    fn find(&self, name: &str) -> Option<&Entry> {
+        self.entries.iter()
+            .filter_map(|entry| entry.as_ref())
+            .find(|entry| entry.name() == name.as_bytes())
+    }
+
+    fn allocate<T: SharedLayout>(&mut self, name: &str) -> Result<Entry> {
+        if mem::align_of::<T>() > ARENA_ALIGN {
+            return Err(Error::Sys(Errno::EINVAL));
+        }
+
+        let slot = self.entries.iter()
+            .position(|entry| entry.is_none())
+            .ok_or(Error::Sys(Errno::ENOMEM))?;
+
+        let align = mem::align_of::<T>();
+        let offset = self.allocated.div_ceil(align) * align;
+        if offset + mem::size_of::<T>() > ARENA_SIZE {
+            return Err(Error::Sys(Errno::ENOMEM));
+        }
+
+        let mut entry = Entry {
+            name: [0; NAME_LEN],
+            name_len: name.len(),
+            offset,
+            layout: T::layout().digest()
+        };
+        entry.name[..name.len()].copy_from_slice(name.as_bytes());
+
+        self.entries[slot] = SharedOption::some(entry);
+        self.allocated = offset + mem::size_of::<T>();
+        Ok(entry)
+    }
+}
+
+#[repr(C, align(64))]
+struct Arena([u8; ARENA_SIZE]);
+
+unsafe impl SharedLayout for Arena {
+    fn layout() -> TypeLayout {
+        TypeLayout::new::<Self>("Arena")
+            .field("0", 0, |s: &Self| &s.0)
+    }
+}
+
+/// Segment holding many named objects, in the style of Boost.Interprocess
+/// managed shared memory. Objects are placed into a fixed-size arena and
+/// looked up through a directory guarded by the segment's `Mutex`.
+///
+/// Objects are never destroyed: they live as long as the segment does.
+#[repr(C)]
+pub struct ManagedSegment {
+    directory: Mutex<Directory>,
+    arena: UnsafeCell<Arena>
+}
+
+shared_layout!(ManagedSegment { directory, arena });
+
+unsafe impl Sync for ManagedSegment {}
+
+impl PthreadPrimitiveConstructor for ManagedSegment {
+    fn new() -> Self {
+        ManagedSegment {
+            directory: Mutex::new(Directory::new()),
+            arena: UnsafeCell::new(Arena([0; ARENA_SIZE]))
+        }
+    }
+
+    fn pshared() -> Self {
+        ManagedSegment {
+            directory: Mutex::pshared(Directory::new()),
+            arena: UnsafeCell::new(Arena([0; ARENA_SIZE]))
+        }
+    }
+}
+
+#[allow(dead_code)]
+impl ManagedSegment {
+    /// Looks up the object registered under `name`.
+    /// Fails with `EINVAL` if it was constructed with a different type.
+    pub fn find<T: Sync + SharedLayout>(&self, name: &str) -> Result<Option<&T>> {
+        let directory = self.directory.lock()?;
+        match directory.find(name) {
+            Some(entry) => self.get(entry).map(Some),
+            None => Ok(None)
+        }
+    }
+
+    /// Looks up the object registered under `name`, constructing it with
+    /// `init` if there is none yet. `init` runs under the directory lock,
+    /// so it must not access the segment itself.
+    pub fn find_or_construct<T, F>(&self, name: &str, init: F) -> Result<&T>
+        where T: Sync + SharedLayout,
+              F: FnOnce() -> T
+    {
+        if name.len() > NAME_LEN {
+            return Err(Error::Sys(Errno::ENAMETOOLONG));
+        }
+
+        let mut directory = self.directory.lock()?;
+        if let Some(entry) = directory.find(name) {
+            return self.get(entry);
+        }
+
+        // Construct before registering, so a panicking `init` leaves no
+        // entry pointing to uninitialized memory behind.
+        let value = init();
+        let entry = directory.allocate::<T>(name)?;
+        unsafe {
+            let object = self.object_ptr::<T>(&entry);
+            ptr::write(object, value);
+            Ok(&*object)
+        }
+    }
+
+    fn get<T: SharedLayout>(&self, entry: &Entry) -> Result<&T> {
+        if entry.layout != T::layout().digest() {
+            return Err(Error::Sys(Errno::EINVAL));
+        }
+
+        unsafe {
+            Ok(&*self.object_ptr::<T>(entry))
+        }
+    }
+
+    unsafe fn object_ptr<T>(&self, entry: &Entry) -> *mut T {
+        (*self.arena.get()).0.as_mut_ptr().add(entry.offset) as *mut T
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ManagedSegment;
+    use ::pthread::PthreadPrimitiveConstructor;
+    use ::queue::Queue;
+    use ::shm::Shm;
+    use ::process;
+    use std::panic::{self, AssertUnwindSafe};
+
+    #[test]
+    fn find_or_construct() {
+        let segment = ManagedSegment::new();
+        assert_eq!(Ok(None), segment.find::<u64>("counter"));
+
+        assert_eq!(Ok(&1u64), segment.find_or_construct("counter", || 1u64));
+        assert_eq!(Ok(&1u64), segment.find_or_construct("counter", || 2u64));
+        assert_eq!(Ok(Some(&1u64)), segment.find::<u64>("counter"));
+
+        assert_eq!(Ok(&[3u8; 3]), segment.find_or_construct("bytes", || [3u8; 3]));
+        assert_eq!(Ok(&1u64), segment.find_or_construct("counter", || 2u64));
+    }
+
+    #[test]
+    fn type_mismatch() {
+        let segment = ManagedSegment::new();
+        segment.find_or_construct("counter", || 1u64).unwrap();
+        assert!(segment.find::<u32>("counter").is_err());
+        assert!(segment.find_or_construct("counter", || 1i64).is_err());
+    }
+
+    #[test]
+    fn exhaustion() {
+        let segment = ManagedSegment::new();
+        assert!(segment.find_or_construct(&"x".repeat(33), || 0u8).is_err());
+        assert!(segment.find_or_construct("huge", || [0u8; super::ARENA_SIZE + 1]).is_err());
+        for i in 0..super::DIRECTORY_SIZE {
+            segment.find_or_construct(&i.to_string(), || i).unwrap();
+        }
+        assert!(segment.find_or_construct("extra", || 0usize).is_err());
+    }
+
+    #[test]
+    fn panicking_init() {
+        let segment = ManagedSegment::new();
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            segment.find_or_construct::<u64, _>("counter", || panic!("init failed"))
+        }));
+        assert!(result.is_err());
+        assert_eq!(Ok(None), segment.find::<u64>("counter"));
+        assert_eq!(Ok(&1u64), segment.find_or_construct("counter", || 1u64));
+    }
+
+    #[test]
+    fn token() {
+        let segment = Shm::new(ManagedSegment::pshared()).unwrap();
+        segment.find_or_construct("counter", || 1u64).unwrap();
+        let other = Shm::<ManagedSegment>::from_token(&segment.token()).unwrap();
+        assert_eq!(Ok(Some(&1u64)), other.find::<u64>("counter"));
+    }
+
+    #[test]
+    fn ipc() {
+        let segment = Shm::new(ManagedSegment::pshared()).unwrap();
+        let queue: &Queue<_> = segment.find_or_construct("queue", Queue::pshared).unwrap();
+
+        {
+            let segment = segment.clone();
+            process::spawn(move || {
+                let queue = segment.find::<Queue<i32>>("queue").unwrap().unwrap();
+                for i in 0..100 {
+                    queue.push(i).unwrap();
+                }
+            }).unwrap();
+        }
+
+        for i in 0..100 {
+            assert_eq!(Ok(i), queue.pop());
+        }
+    }
+}
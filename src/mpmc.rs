@@ -0,0 +1,368 @@
+use nix::Result;
+use nix::Error;
+use nix::Errno;
+use ::futex::Event;
+use ::pthread::PthreadPrimitiveConstructor;
+use ::queue::{BlockingQueue, Full, RING_BUFFER_SIZE};
+use ::spsc::CachePadded;
+use ::layout::{SharedLayout, TypeLayout};
+
+use std::array;
+use std::cell::UnsafeCell;
+use std::mem;
+use std::mem::MaybeUninit;
+use std::result;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+#[repr(C)]
+struct Slot<T> {
+    seq: AtomicUsize,
+    value: UnsafeCell<MaybeUninit<T>>
+}
+
+unsafe impl<T: SharedLayout> SharedLayout for Slot<T> {
+    fn layout() -> TypeLayout {
+        TypeLayout::new::<Self>("Slot")
+            .field("seq", mem::offset_of!(Self, seq), |s: &Self| &s.seq)
+            .field("value", mem::offset_of!(Self, value), |s: &Self| &s.value)
+    }
+}
+
+/// Lock-free bounded queue for many producers and consumers, with up to `N`
+/// values, which must be a power of two.
+///
+/// Every slot carries a sequence number telling whether it is ready to be
+/// written or read at a given position (D. Vyukov's bounded MPMC queue).
+/// Blocking calls sleep on a futex only while the queue is full or empty.
+#[repr(C)]
+pub struct MpmcQueue<T, const N: usize = RING_BUFFER_SIZE>
+    where T: Copy
+{
+    enqueue_pos: CachePadded<AtomicUsize>,
+    dequeue_pos: CachePadded<AtomicUsize>,
+    readable: CachePadded<Event>,
+    writable: CachePadded<Event>,
+    slots: [Slot<T>; N]
+}
+
+unsafe impl<T: Copy + Send, const N: usize> Sync for MpmcQueue<T, N> {}
+
+unsafe impl<T, const N: usize> SharedLayout for MpmcQueue<T, N>
+    where T: Copy + SharedLayout
+{
+    fn layout() -> TypeLayout {
+        TypeLayout::new::<Self>("MpmcQueue")
+            .field("enqueue_pos", mem::offset_of!(Self, enqueue_pos), |s: &Self| &s.enqueue_pos)
+            .field("dequeue_pos", mem::offset_of!(Self, dequeue_pos), |s: &Self| &s.dequeue_pos)
+            .field("readable", mem::offset_of!(Self, readable), |s: &Self| &s.readable)
+            .field("writable", mem::offset_of!(Self, writable), |s: &Self| &s.writable)
+            .field("slots", mem::offset_of!(Self, slots), |s: &Self| &s.slots)
+    }
+}
+
+impl<T, const N: usize> PthreadPrimitiveConstructor for MpmcQueue<T, N>
+    where T: Copy
+{
+    fn new() -> Self {
+        assert!(N.is_power_of_two(), "queue capacity must be a power of two");
+        MpmcQueue {
+            enqueue_pos: CachePadded(AtomicUsize::new(0)),
+            dequeue_pos: CachePadded(AtomicUsize::new(0)),
+            readable: CachePadded(Event::new()),
+            writable: CachePadded(Event::new()),
+            slots: array::from_fn(|i| Slot {
+                seq: AtomicUsize::new(i),
+                value: UnsafeCell::new(MaybeUninit::uninit())
+            })
+        }
+    }
+
+    // Futexes are process-shared as long as they live in shared memory
+    fn pshared() -> Self {
+        Self::new()
+    }
+}
+
+#[allow(dead_code)]
+impl<T, const N: usize> MpmcQueue<T, N>
+    where T: Copy
+{
+    pub fn capacity(&self) -> usize {
+        N
+    }
+
+    /// Pushes `value` unless the queue is full, handing it back otherwise.
+    pub fn try_push(&self, value: T) -> Result<result::Result<(), Full<T>>> {
+        let mut pos = self.enqueue_pos.0.load(Ordering::Relaxed);
+        loop {
+            let slot = &self.slots[pos & (N - 1)];
+            let seq = slot.seq.load(Ordering::Acquire);
+            let diff = seq.wrapping_sub(pos) as isize;
+
+            if diff == 0 {
+                match self.enqueue_pos.0.compare_exchange_weak(pos, pos.wrapping_add(1),
+                                                               Ordering::Relaxed, Ordering::Relaxed) {
+                    Ok(_) => {
+                        unsafe {
+                            *slot.value.get() = MaybeUninit::new(value);
+                        }
+                        slot.seq.store(pos.wrapping_add(1), Ordering::Release);
+                        self.readable.0.notify_all()?;
+                        return Ok(Ok(()));
+                    },
+                    Err(current) => pos = current
+                }
+            } else if diff < 0 {
+                return Ok(Err(Full(value)));
+            } else {
+                pos = self.enqueue_pos.0.load(Ordering::Relaxed);
+            }
+        }
+    }
+
+    pub fn push(&self, value: T) -> Result<()> {
+        match self.push_until(value, None)? {
+            Ok(()) => Ok(()),
+            Err(_) => unreachable!("push without deadline timed out")
+        }
+    }
+
+    pub fn timed_push(&self, value: T, time: Duration) -> Result<result::Result<(), Full<T>>> {
+        self.push_until(value, Some(Instant::now() + time))
+    }
+
+    pub fn push_deadline(&self, value: T, deadline: Instant) -> Result<result::Result<(), Full<T>>> {
+        self.push_until(value, Some(deadline))
+    }
+
+    fn push_until(&self, value: T, deadline: Option<Instant>) -> Result<result::Result<(), Full<T>>> {
+        let mut value = value;
+        loop {
+            match self.try_push(value)? {
+                Ok(()) => return Ok(Ok(())),
+                Err(Full(rejected)) => value = rejected
+            }
+
+            let key = self.writable.0.prepare();
+            match self.try_push(value)? {
+                Ok(()) => {
+                    self.writable.0.cancel();
+                    return Ok(Ok(()));
+                },
+                Err(Full(rejected)) => value = rejected
+            }
+            match self.writable.0.wait(key, deadline) {
+                Err(Error::Sys(Errno::ETIMEDOUT)) => return self.try_push(value),
+                Err(err) => return Err(err),
+                Ok(()) => ()
+            }
+        }
+    }
+
+    pub fn try_pop(&self) -> Result<Option<T>> {
+        let mut pos = self.dequeue_pos.0.load(Ordering::Relaxed);
+        loop {
+            let slot = &self.slots[pos & (N - 1)];
+            let seq = slot.seq.load(Ordering::Acquire);
+            let diff = seq.wrapping_sub(pos.wrapping_add(1)) as isize;
+
+            if diff == 0 {
+                match self.dequeue_pos.0.compare_exchange_weak(pos, pos.wrapping_add(1),
+                                                               Ordering::Relaxed, Ordering::Relaxed) {
+                    Ok(_) => {
+                        let value = unsafe {
+                            (*slot.value.get()).assume_init()
+                        };
+                        slot.seq.store(pos.wrapping_add(N), Ordering::Release);
+                        self.writable.0.notify_all()?;
+                        return Ok(Some(value));
+                    },
+                    Err(current) => pos = current
+                }
+            } else if diff < 0 {
+                return Ok(None);
+            } else {
+                pos = self.dequeue_pos.0.load(Ordering::Relaxed);
+            }
+        }
+    }
+
+    pub fn pop(&self) -> Result<T> {
+        loop {
+            if let Some(value) = self.pop_until(None)? {
+                return Ok(value);
+            }
+        }
+    }
+
+    pub fn timed_pop(&self, time: Duration) -> Result<Option<T>> {
+        self.pop_until(Some(Instant::now() + time))
+    }
+
+    pub fn pop_deadline(&self, deadline: Instant) -> Result<Option<T>> {
+        self.pop_until(Some(deadline))
+    }
+
+    fn pop_until(&self, deadline: Option<Instant>) -> Result<Option<T>> {
+        loop {
+            if let Some(value) = self.try_pop()? {
+                return Ok(Some(value));
+            }
+
+            let key = self.readable.0.prepare();
+            if let Some(value) = self.try_pop()? {
+                self.readable.0.cancel();
+                return Ok(Some(value));
+            }
+            match self.readable.0.wait(key, deadline) {
+                Err(Error::Sys(Errno::ETIMEDOUT)) => return self.try_pop(),
+                Err(err) => return Err(err),
+                Ok(()) => ()
+            }
+        }
+    }
+}
+
+impl<T, const N: usize> BlockingQueue<T> for MpmcQueue<T, N>
+    where T: Copy
+{
+    fn push(&self, value: T) -> Result<()> {
+        MpmcQueue::push(self, value)
+    }
+
+    fn try_push(&self, value: T) -> Result<result::Result<(), Full<T>>> {
+        MpmcQueue::try_push(self, value)
+    }
+
+    fn timed_push(&self, value: T, time: Duration) -> Result<result::Result<(), Full<T>>> {
+        MpmcQueue::timed_push(self, value, time)
+    }
+
+    fn push_deadline(&self, value: T, deadline: Instant) -> Result<result::Result<(), Full<T>>> {
+        MpmcQueue::push_deadline(self, value, deadline)
+    }
+
+    fn pop(&self) -> Result<T> {
+        MpmcQueue::pop(self)
+    }
+
+    fn try_pop(&self) -> Result<Option<T>> {
+        MpmcQueue::try_pop(self)
+    }
+
+    fn timed_pop(&self, time: Duration) -> Result<Option<T>> {
+        MpmcQueue::timed_pop(self, time)
+    }
+
+    fn pop_deadline(&self, deadline: Instant) -> Result<Option<T>> {
+        MpmcQueue::pop_deadline(self, deadline)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::MpmcQueue;
+    use ::pthread::PthreadPrimitiveConstructor;
+    use ::queue::{BlockingQueue, Full, FutexQueue, Queue, RING_BUFFER_SIZE};
+    use ::spsc::SpscQueue;
+    use ::process;
+    use ::shm::Shm;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn try_push_pop() {
+        let queue: MpmcQueue<i32, 2> = MpmcQueue::new();
+        assert_eq!(Ok(None), queue.try_pop());
+        assert_eq!(Ok(Ok(())), queue.try_push(1));
+        assert_eq!(Ok(Ok(())), queue.try_push(2));
+        assert_eq!(Ok(Err(Full(3))), queue.try_push(3));
+        assert_eq!(Ok(Some(1)), queue.try_pop());
+        assert_eq!(Ok(Ok(())), queue.try_push(3));
+        assert_eq!(Ok(Some(2)), queue.try_pop());
+        assert_eq!(Ok(Some(3)), queue.try_pop());
+        assert_eq!(Ok(None), queue.try_pop());
+    }
+
+    #[test]
+    fn timed_pop() {
+        let queue: MpmcQueue<i32> = MpmcQueue::new();
+        let start = Instant::now();
+        assert_eq!(Ok(None), queue.timed_pop(Duration::from_millis(10)));
+        assert!(start.elapsed() >= Duration::from_millis(10));
+    }
+
+    #[test]
+    fn mpmc() {
+        let queue: Shm<MpmcQueue<u64>> = Shm::new(MpmcQueue::new()).unwrap();
+
+        let producers = (0..4).map(|p| {
+            let queue = queue.clone();
+            thread::spawn(move || {
+                for i in 0..2500 {
+                    queue.push(p * 2500 + i).unwrap();
+                }
+            })
+        }).collect::<Vec<_>>();
+
+        let consumers = (0..4).map(|_| {
+            let queue = queue.clone();
+            thread::spawn(move || {
+                (0..2500).map(|_| queue.pop().unwrap()).sum::<u64>()
+            })
+        }).collect::<Vec<_>>();
+
+        for producer in producers {
+            producer.join().unwrap();
+        }
+        let sum: u64 = consumers.into_iter().map(|c| c.join().unwrap()).sum();
+        assert_eq!((0..10000).sum::<u64>(), sum);
+    }
+
+    #[test]
+    fn mpmc_ipc() {
+        let queue: Shm<MpmcQueue<u64>> = Shm::new(MpmcQueue::pshared()).unwrap();
+
+        for p in 0..4 {
+            let queue = queue.clone();
+            process::spawn(move || {
+                for i in 0..2500 {
+                    queue.push(p * 2500 + i).unwrap();
+                }
+            }).unwrap();
+        }
+
+        let sum: u64 = (0..10000).map(|_| queue.pop().unwrap()).sum();
+        assert_eq!((0..10000).sum::<u64>(), sum);
+        assert_eq!(Ok(None), queue.try_pop());
+    }
+
+    fn roundtrip<Q: BlockingQueue<i32>>(queue: &Q) {
+        for i in 0..4 {
+            queue.push(i).unwrap();
+        }
+        assert_eq!(Ok(0), queue.pop());
+        assert_eq!(Ok(Some(1)), queue.try_pop());
+        assert_eq!(Ok(Some(2)), queue.timed_pop(Duration::from_millis(1)));
+        assert_eq!(Ok(Some(3)), queue.timed_pop(Duration::from_millis(1)));
+        assert_eq!(Ok(None), queue.timed_pop(Duration::from_millis(1)));
+
+        for i in 0..RING_BUFFER_SIZE as i32 {
+            assert_eq!(Ok(Ok(())), queue.try_push(i));
+        }
+        assert_eq!(Ok(Err(Full(-1))), queue.try_push(-1));
+        assert_eq!(Ok(Err(Full(-1))), queue.timed_push(-1, Duration::from_millis(1)));
+        assert_eq!(Ok(Err(Full(-1))), queue.push_deadline(-1, Instant::now()));
+        assert_eq!(Ok(0), queue.pop());
+        assert_eq!(Ok(Ok(())), queue.push_deadline(-1, Instant::now() + Duration::from_millis(1)));
+    }
+
+    #[test]
+    fn shared_trait() {
+        roundtrip(&SpscQueue::<i32>::new());
+        roundtrip(&MpmcQueue::<i32>::new());
+        roundtrip(&Queue::<i32>::new());
+        roundtrip(&FutexQueue::<i32>::new());
+    }
+}
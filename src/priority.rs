@@ -0,0 +1,383 @@
+use nix::Error;
+use nix::Errno;
+use ::pthread::PthreadPrimitiveConstructor;
+use ::pthread::PthreadWrappingPrimitiveConstructor;
+use ::pthread::Condvar;
+use ::pthread::Mutex;
+use ::pthread::MutexGuard;
+use ::queue::RING_BUFFER_SIZE;
+use ::shm::{Checkpoint, PlainData};
+use ::layout::{SharedLayout, SharedOption, TypeLayout};
+
+use std::cmp::Ordering;
+use std::mem;
+use std::time::{Duration, Instant};
+
+/// Blocking queue holding up to `N` values, popping the one with the highest
+/// priority first. Values of equal priority are popped in FIFO order.
+#[repr(C)]
+pub struct PriorityQueue<T, P, const N: usize = RING_BUFFER_SIZE>
+    where T: Copy, P: Copy + Ord
+{
+    heap: Mutex<Heap<T, P, N>>,
+    in_cond: Condvar,
+    out_cond: Condvar
+}
+
+impl<T, P, const N: usize> PthreadPrimitiveConstructor for PriorityQueue<T, P, N>
+    where T: Copy, P: Copy + Ord
+{
+    fn new() -> Self {
+        PriorityQueue {
+            heap: Mutex::new(Heap::new()),
+            in_cond: Condvar::new(),
+            out_cond: Condvar::new(),
+        }
+    }
+
+    fn pshared() -> Self {
+        PriorityQueue {
+            heap: Mutex::pshared(Heap::new()),
+            in_cond: Condvar::pshared(),
+            out_cond: Condvar::pshared(),
+        }
+    }
+}
+
+unsafe impl<T, P, const N: usize> SharedLayout for PriorityQueue<T, P, N>
+    where T: Copy + SharedLayout, P: Copy + Ord + SharedLayout
+{
+    fn layout() -> TypeLayout {
+        TypeLayout::new::<Self>("PriorityQueue")
+            .field("heap", mem::offset_of!(Self, heap), |s: &Self| &s.heap)
+            .field("in_cond", mem::offset_of!(Self, in_cond), |s: &Self| &s.in_cond)
+            .field("out_cond", mem::offset_of!(Self, out_cond), |s: &Self| &s.out_cond)
+    }
+}
+
+unsafe impl<T, P, const N: usize> Checkpoint for PriorityQueue<T, P, N>
+    where T: PlainData, P: PlainData + Ord
+{
+    type Guard<'a> = MutexGuard<'a, Heap<T, P, N>> where T: 'a, P: 'a;
+
+    fn freeze(&self) -> Result<MutexGuard<'_, Heap<T, P, N>>, Error> {
+        self.heap.freeze()
+    }
+
+    fn thaw(&mut self) {
+        self.heap.thaw();
+        self.in_cond.thaw();
+        self.out_cond.thaw();
+    }
+}
+
+#[allow(dead_code)]
+impl<T, P, const N: usize> PriorityQueue<T, P, N>
+    where T: Copy, P: Copy + Ord
+{
+    pub fn capacity(&self) -> usize {
+        N
+    }
+
+    /// Pushes `value` with `priority`, blocking while the queue is full.
+    pub fn push(&self, value: T, priority: P) -> Result<(), Error> {
+        let mut guard = self.heap.lock()?;
+        while !guard.push(value, priority) {
+            guard = self.out_cond.wait(guard)?;
+        }
+
+        self.in_cond.signal()
+    }
+
+    /// Pops the value with the highest priority, blocking while the queue is empty.
+    pub fn pop(&self) -> Result<T, Error> {
+        let value = {
+            let mut guard = self.heap.lock()?;
+            loop {
+                if let Some(value) = guard.pop() {
+                    break value;
+                }
+                guard = self.in_cond.wait(guard)?;
+            }
+        };
+
+        self.out_cond.signal()?;
+        Ok(value)
+    }
+
+    pub fn try_pop(&self) -> Result<Option<T>, Error> {
+        let value = self.heap.lock()?.pop();
+        if value.is_some() {
+            self.out_cond.signal()?;
+        }
+        Ok(value)
+    }
+
+    pub fn timed_pop(&self, time: Duration) -> Result<Option<T>, Error> {
+        self.pop_deadline(Instant::now() + time)
+    }
+
+    /// Pops the value with the highest priority, blocking until `deadline`
+    /// at most while the queue is empty.
+    pub fn pop_deadline(&self, deadline: Instant) -> Result<Option<T>, Error> {
+        let value = {
+            let mut guard = self.heap.lock()?;
+            loop {
+                if let Some(value) = guard.pop() {
+                    break Some(value);
+                }
+                if Instant::now() >= deadline {
+                    break None;
+                }
+                guard = match self.in_cond.wait_until(guard, deadline) {
+                    Err(Error::Sys(Errno::ETIMEDOUT)) => self.heap.lock()?,
+                    Err(err) => return Err(err),
+                    Ok(guard) => guard
+                };
+            }
+        };
+
+        if value.is_some() {
+            self.out_cond.signal()?;
+        }
+        Ok(value)
+    }
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug)]
+struct Entry<T, P> {
+    priority: P,
+    seq: u64,
+    value: T
+}
+
+impl<T, P: Ord> Entry<T, P> {
+    /// Higher priority first, then the earlier push.
+    fn precedes(&self, other: &Self) -> bool {
+        match self.priority.cmp(&other.priority) {
+            Ordering::Greater => true,
+            Ordering::Less => false,
+            Ordering::Equal => self.seq < other.seq
+        }
+    }
+}
+
+unsafe impl<T: SharedLayout, P: SharedLayout> SharedLayout for Entry<T, P> {
+    fn layout() -> TypeLayout {
+        TypeLayout::new::<Self>("Entry")
+            .field("priority", mem::offset_of!(Self, priority), |s: &Self| &s.priority)
+            .field("seq", mem::offset_of!(Self, seq), |s: &Self| &s.seq)
+            .field("value", mem::offset_of!(Self, value), |s: &Self| &s.value)
+    }
+}
+
+/// Fixed-capacity binary heap, with a push counter to keep equal priorities
+/// in FIFO order.
+#[repr(C)]
+#[derive(Copy, Clone, Debug)]
+pub struct Heap<T, P, const N: usize>
+    where T: Copy, P: Copy + Ord
+{
+    len: usize,
+    next_seq: u64,
+    entries: [SharedOption<Entry<T, P>>; N]
+}
+
+unsafe impl<T, P, const N: usize> SharedLayout for Heap<T, P, N>
+    where T: Copy + SharedLayout, P: Copy + Ord + SharedLayout
+{
+    fn layout() -> TypeLayout {
+        TypeLayout::new::<Self>("Heap")
+            .field("len", mem::offset_of!(Self, len), |s: &Self| &s.len)
+            .field("next_seq", mem::offset_of!(Self, next_seq), |s: &Self| &s.next_seq)
+            .field("entries", mem::offset_of!(Self, entries), |s: &Self| &s.entries)
+    }
+}
+
+unsafe impl<T, P, const N: usize> PlainData for Heap<T, P, N>
+    where T: PlainData, P: PlainData + Ord
+{}
+
+impl<T, P, const N: usize> Heap<T, P, N>
+    where T: Copy, P: Copy + Ord
+{
+    pub fn new() -> Self {
+        assert!(N > 0, "priority queue capacity must not be zero");
+        Heap {
+            len: 0,
+            next_seq: 0,
+            entries: [SharedOption::none(); N]
+        }
+    }
+
+    /// Pushes `value` unless the heap is full.
+    pub fn push(&mut self, value: T, priority: P) -> bool {
+        if self.len == N {
+            return false;
+        }
+
+        self.entries[self.len] = SharedOption::some(Entry { priority, seq: self.next_seq, value });
+        self.next_seq += 1;
+        self.len += 1;
+        self.sift_up(self.len - 1);
+        true
+    }
+
+    pub fn pop(&mut self) -> Option<T> {
+        if self.len == 0 {
+            return None;
+        }
+
+        self.len -= 1;
+        self.entries.swap(0, self.len);
+        let top = self.entries[self.len].take();
+        self.sift_down(0);
+        top.map(|entry| entry.value)
+    }
+
+    fn entry(&self, idx: usize) -> &Entry<T, P> {
+        self.entries[idx].as_ref().expect("heap entry within len")
+    }
+
+    fn sift_up(&mut self, mut idx: usize) {
+        while idx > 0 {
+            let parent = (idx - 1) / 2;
+            if !self.entry(idx).precedes(self.entry(parent)) {
+                break;
+            }
+            self.entries.swap(idx, parent);
+            idx = parent;
+        }
+    }
+
+    fn sift_down(&mut self, mut idx: usize) {
+        loop {
+            let mut first = idx;
+            for child in [2 * idx + 1, 2 * idx + 2] {
+                if child < self.len && self.entry(child).precedes(self.entry(first)) {
+                    first = child;
+                }
+            }
+            if first == idx {
+                break;
+            }
+            self.entries.swap(idx, first);
+            idx = first;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    mod heap {
+        use super::super::Heap;
+
+        #[test]
+        fn order() {
+            let mut heap: Heap<char, u8, 8> = Heap::new();
+            assert_eq!(None, heap.pop());
+            for &(value, priority) in &[('a', 1), ('b', 3), ('c', 1), ('d', 2), ('e', 3), ('f', 1)] {
+                assert!(heap.push(value, priority));
+            }
+            let popped = (0..6).map(|_| heap.pop().unwrap()).collect::<String>();
+            assert_eq!("bedacf", popped);
+            assert_eq!(None, heap.pop());
+        }
+
+        #[test]
+        fn overflow() {
+            let mut heap: Heap<i32, i32, 2> = Heap::new();
+            assert!(heap.push(1, 0));
+            assert!(heap.push(2, 0));
+            assert!(!heap.push(3, 1));
+            assert_eq!(Some(1), heap.pop());
+            assert!(heap.push(3, 1));
+            assert_eq!(Some(3), heap.pop());
+            assert_eq!(Some(2), heap.pop());
+        }
+    }
+
+    mod priority_queue {
+        use super::super::PriorityQueue;
+        use ::pthread::PthreadPrimitiveConstructor;
+        use ::process;
+        use ::shm::Shm;
+        use std::sync::Arc;
+        use std::sync::atomic::{AtomicBool, Ordering};
+        use std::thread;
+        use std::time::Duration;
+
+        #[test]
+        fn timed_pop() {
+            let queue: PriorityQueue<i32, u8> = PriorityQueue::new();
+            assert_eq!(Ok(None), queue.timed_pop(Duration::from_millis(10)));
+            queue.push(1, 0).unwrap();
+            queue.push(2, 1).unwrap();
+            assert_eq!(Ok(Some(2)), queue.timed_pop(Duration::from_millis(10)));
+            assert_eq!(Ok(Some(1)), queue.try_pop());
+            assert_eq!(Ok(None), queue.try_pop());
+        }
+
+        #[test]
+        fn urgent_overtakes_bulk() {
+            let queue: PriorityQueue<u32, u8, 4> = PriorityQueue::new();
+            for i in 0..4 {
+                queue.push(i, 0).unwrap();
+            }
+            assert_eq!(Ok(0), queue.pop());
+
+            queue.push(u32::MAX, 1).unwrap();
+            let popped = (0..4).map(|_| queue.pop().unwrap()).collect::<Vec<_>>();
+            assert_eq!(vec![u32::MAX, 1, 2, 3], popped);
+        }
+
+        #[test]
+        fn blocked_push() {
+            let queue: Shm<PriorityQueue<u32, u8, 2>> = Shm::new(PriorityQueue::new()).unwrap();
+            queue.push(1, 0).unwrap();
+            queue.push(2, 0).unwrap();
+
+            let pushed = Arc::new(AtomicBool::new(false));
+            let producer = {
+                let queue = queue.clone();
+                let pushed = pushed.clone();
+                thread::spawn(move || {
+                    queue.push(3, 1).unwrap();
+                    pushed.store(true, Ordering::SeqCst);
+                })
+            };
+
+            thread::sleep(Duration::from_millis(10));
+            assert!(!pushed.load(Ordering::SeqCst));
+            assert_eq!(Ok(1), queue.pop());
+            producer.join().unwrap();
+            assert!(pushed.load(Ordering::SeqCst));
+
+            assert_eq!(Ok(3), queue.pop());
+            assert_eq!(Ok(2), queue.pop());
+        }
+
+        #[test]
+        fn priority_ipc() {
+            let queue: Shm<PriorityQueue<u32, u8, 16>> = Shm::new(PriorityQueue::pshared()).unwrap();
+
+            let producer = {
+                let queue = queue.clone();
+                process::spawn(move || {
+                    for i in 0..16 {
+                        queue.push(i, (i % 4) as u8).unwrap();
+                    }
+                }).unwrap()
+            };
+            match producer.wait(None).unwrap() {
+                process::WaitStatus::Exited(_, 0) => (),
+                other => panic!("producer failed: {:?}", other)
+            }
+
+            let popped = (0..16).map(|_| queue.pop().unwrap()).collect::<Vec<_>>();
+            assert_eq!(vec![3, 7, 11, 15, 2, 6, 10, 14, 1, 5, 9, 13, 0, 4, 8, 12], popped);
+            assert_eq!(Ok(None), queue.try_pop());
+        }
+    }
+}
@@ -2,6 +2,7 @@ use nix::unistd::{fork, ForkResult};
 use nix::sys::wait::waitpid;
 use nix::sys::signal::kill;
 use nix::Result;
+use std::cell::Cell;
 use std::process::exit;
 use nix::libc;
 
@@ -30,9 +31,51 @@ impl Process {
     }
 }
 
+thread_local! {
+    static HANDING_OVER: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Whether the parent is dropping its copy of values moved into a child by
+/// `spawn`. Handles to shared state return early from `drop` then, since the
+/// child owns them now and releases them itself.
+pub fn handing_over() -> bool {
+    HANDING_OVER.with(Cell::get)
+}
+
+/// Sets `HANDING_OVER` while alive, and resets it even if a drop panics.
+struct HandOver;
+
+impl HandOver {
+    fn new() -> Self {
+        HANDING_OVER.with(|flag| flag.set(true));
+        HandOver
+    }
+}
+
+impl Drop for HandOver {
+    fn drop(&mut self) {
+        HANDING_OVER.with(|flag| flag.set(false));
+    }
+}
+
+/// Forks and runs `child_main` in the child process.
+///
+/// Values moved into `child_main` belong to the child, which drops them
+/// once `child_main` returns. The parent still drops its copy, so that
+/// process-local resources like open files are closed there too, but with
+/// `handing_over` set.
+///
+/// Every type which releases shared state when dropped, like the reference
+/// count of a `Shm` or the sender count of a queue `Sender`, must return
+/// early from `drop` while `handing_over` is set, or the state is released
+/// once per process. Values which stay in the parent are released as usual.
 pub fn spawn<T: FnOnce()>(child_main: T) -> Result<Process> {
     match fork()? {
-        ForkResult::Parent{ child: pid } => Ok(Process::new(pid)),
+        ForkResult::Parent{ child: pid } => {
+            let _hand_over = HandOver::new();
+            drop(child_main);
+            Ok(Process::new(pid))
+        },
         ForkResult::Child => {
             child_main();
             exit(0);
@@ -48,6 +91,8 @@ pub fn pid() -> i32 {
 
 #[cfg(test)]
 mod test {
+    use std::cell::Cell;
+    use std::rc::Rc;
     use std::thread;
     use std::time::Duration;
     use nix::sys::wait::{WaitStatus};
@@ -58,6 +103,24 @@ mod test {
         assert!(child.pid() != 0);
     }
 
+    #[test]
+    fn hand_over() {
+        struct Probe(Rc<Cell<Option<bool>>>);
+
+        impl Drop for Probe {
+            fn drop(&mut self) {
+                self.0.set(Some(super::handing_over()));
+            }
+        }
+
+        // The parent still drops its copy of the closure, flagged as such
+        let dropped = Rc::new(Cell::new(None));
+        let probe = Probe(dropped.clone());
+        super::spawn(move || drop(probe)).unwrap().wait(None).unwrap();
+        assert_eq!(Some(true), dropped.get());
+        assert!(!super::handing_over());
+    }
+
     #[test]
     fn wait_exit() {
         let child = super::spawn(|| thread::sleep(Duration::from_millis(10)))
@@ -3,18 +3,26 @@ use nix::Error;
 use nix::Errno;
 use std::cell::UnsafeCell;
 use std::mem;
-use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use std::mem::MaybeUninit;
+use std::time::Instant;
 use std::fmt;
+use ::shm::{Checkpoint, PlainData};
+use ::layout::{SharedLayout, TypeLayout};
 
 use nix::libc::{
-    PTHREAD_COND_INITIALIZER,
     pthread_cond_t,
     pthread_cond_init,
     pthread_cond_wait,
     pthread_cond_timedwait,
     pthread_cond_signal,
+    pthread_cond_broadcast,
+    pthread_condattr_t,
     pthread_condattr_init,
     pthread_condattr_setpshared,
+    pthread_condattr_setclock,
+    pthread_condattr_destroy,
+    clock_gettime,
+    CLOCK_MONOTONIC,
 
     PTHREAD_MUTEX_INITIALIZER,
     pthread_mutex_t,
@@ -45,20 +53,28 @@ pub trait PthreadWrappingPrimitiveConstructor<T> {
     fn pshared(data: T) -> Self;
 }
 
+/// Condvars measure timeouts with CLOCK_MONOTONIC, so they are not
+/// affected by changes of the wall clock.
+fn init_cond(pshared: bool) -> pthread_cond_t {
+    unsafe {
+        let mut condattr = MaybeUninit::<pthread_condattr_t>::uninit();
+        pthread_condattr_init(condattr.as_mut_ptr());
+        pthread_condattr_setpshared(condattr.as_mut_ptr(), pshared as i32);
+        pthread_condattr_setclock(condattr.as_mut_ptr(), CLOCK_MONOTONIC);
+        let mut cond = MaybeUninit::uninit();
+        pthread_cond_init(cond.as_mut_ptr(), condattr.as_ptr());
+        pthread_condattr_destroy(condattr.as_mut_ptr());
+        cond.assume_init()
+    }
+}
+
 impl PthreadPrimitiveConstructor for pthread_cond_t {
     fn new() -> Self {
-        PTHREAD_COND_INITIALIZER
+        init_cond(false)
     }
 
     fn pshared() -> Self {
-        unsafe {
-            let condattr = UnsafeCell::new(mem::uninitialized());
-            pthread_condattr_init(condattr.get());
-            pthread_condattr_setpshared(condattr.get(), 1);
-            let cond = UnsafeCell::new(mem::uninitialized());
-            pthread_cond_init(cond.get(), condattr.get());
-            cond.into_inner()
-        }
+        init_cond(true)
     }
 }
 
@@ -90,6 +106,9 @@ impl PthreadPrimitiveConstructor for pthread_rwlock_t {
     }
 }
 
+primitive_layout!(pthread_cond_t, pthread_mutex_t);
+
+#[repr(C)]
 pub struct Condvar(UnsafeCell<pthread_cond_t>);
 
 unsafe impl Sync for Condvar {}
@@ -120,14 +139,21 @@ impl Condvar {
         }
     }
 
-    pub fn timed_wait<'a, T>(&self, guard: MutexGuard<'a, T>, duration: Duration) -> Result<MutexGuard<'a, T>> {
-        let abstime = SystemTime::now() + duration; 
-        let duration = abstime.duration_since(UNIX_EPOCH)
-            .unwrap();
+    /// Waits until woken or until `deadline`, failing with `ETIMEDOUT` in
+    /// the latter case. Like `wait`, it may also return spuriously, so
+    /// callers have to recheck their condition.
+    pub fn wait_until<'a, T>(&self, guard: MutexGuard<'a, T>, deadline: Instant) -> Result<MutexGuard<'a, T>> {
+        let mut now = timespec { tv_sec: 0, tv_nsec: 0 };
+        if unsafe { clock_gettime(CLOCK_MONOTONIC, &mut now) } != 0 {
+            return Err(Error::Sys(Errno::last()));
+        }
 
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        let nanos = now.tv_nsec as u64 + remaining.subsec_nanos() as u64;
+        let secs = remaining.as_secs().min(time_t::MAX as u64) as time_t;
         let tv = timespec {
-            tv_sec:  duration.as_secs() as time_t, 
-            tv_nsec: duration.subsec_nanos() as c_long
+            tv_sec:  now.tv_sec.saturating_add(secs).saturating_add((nanos / 1_000_000_000) as time_t),
+            tv_nsec: (nanos % 1_000_000_000) as c_long
         };
 
         let status = unsafe {
@@ -158,10 +184,44 @@ impl Condvar {
             Ok(())
         }
     }
+
+    pub fn broadcast(&self) -> Result<()> {
+        let status = unsafe {
+            pthread_cond_broadcast(
+                self.0.get()
+            )
+        };
+
+        if status != 0 {
+            Err(Error::Sys(Errno::from_i32(status)))
+        } else {
+            Ok(())
+        }
+    }
+}
+
+unsafe impl SharedLayout for Condvar {
+    fn layout() -> TypeLayout {
+        TypeLayout::new::<Self>("Condvar")
+            .field("0", 0, |s: &Self| &s.0)
+    }
+}
+
+unsafe impl Checkpoint for Condvar {
+    type Guard<'a> = ();
+
+    fn freeze(&self) -> Result<()> {
+        Ok(())
+    }
+
+    fn thaw(&mut self) {
+        self.0 = UnsafeCell::new(pthread_cond_t::pshared());
+    }
 }
 
 use std::ops::{Deref, DerefMut, Drop};
 
+#[repr(C)]
 pub struct Mutex<T> {
     lock: UnsafeCell<pthread_mutex_t>,
     data: UnsafeCell<T>
@@ -189,6 +249,34 @@ impl<T> Mutex<T> {
     pub fn lock(&self) -> Result<MutexGuard<T>> {
         MutexGuard::new(self)
     }
+
+    /// Mutable access to the data without locking, as `self` is not shared.
+    #[allow(dead_code)]
+    pub fn get_mut(&mut self) -> &mut T {
+        unsafe {
+            &mut *self.data.get()
+        }
+    }
+}
+
+unsafe impl<T: SharedLayout> SharedLayout for Mutex<T> {
+    fn layout() -> TypeLayout {
+        TypeLayout::new::<Self>("Mutex")
+            .field("lock", mem::offset_of!(Self, lock), |s: &Self| &s.lock)
+            .field("data", mem::offset_of!(Self, data), |s: &Self| &s.data)
+    }
+}
+
+unsafe impl<T: PlainData> Checkpoint for Mutex<T> {
+    type Guard<'a> = MutexGuard<'a, T> where T: 'a;
+
+    fn freeze(&self) -> Result<MutexGuard<'_, T>> {
+        self.lock()
+    }
+
+    fn thaw(&mut self) {
+        self.lock = UnsafeCell::new(pthread_mutex_t::pshared());
+    }
 }
 
 impl<T> fmt::Debug for Mutex<T> 
@@ -215,6 +303,11 @@ impl<'a, T> MutexGuard<'a, T> {
             Ok(MutexGuard(mutex))
         }
     }
+
+    /// Unlocks the mutex, returning it to be locked again.
+    pub fn unlock(self) -> &'a Mutex<T> {
+        self.0
+    }
 }
 
 impl<'a, T> fmt::Debug for MutexGuard<'a, T> 
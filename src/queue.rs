@@ -2,153 +2,1378 @@ use nix;
 use nix::Error;
 use nix::Errno;
 use ::shm::Shm;
+use ::process;
+use ::futex::Event;
 use ::pthread::PthreadPrimitiveConstructor;
 use ::pthread::Condvar;
 
 use ::pthread::PthreadWrappingPrimitiveConstructor;
 use ::pthread::Mutex;
+use ::pthread::MutexGuard;
+use ::shm::{Checkpoint, PlainData};
+use ::layout::{SharedLayout, TypeLayout};
 
-use std::time::Duration;
+use nix::libc;
+use nix::unistd;
+use std::fmt;
+use std::mem;
+use std::mem::MaybeUninit;
+use std::ops::Deref;
+use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
 
 
-pub fn ipc_queue<T: Copy>() -> nix::Result<(Shm<Queue<T>>, Shm<Queue<T>>)> {
-    let queue = Shm::new(Queue::pshared())?;
-    Ok((queue.clone(), queue))
+/// Creates a process-shared queue of capacity `N` and returns its first
+/// sender and receiver.
+pub fn ipc_queue<T: Copy, const N: usize>() -> nix::Result<(Sender<T, N>, Receiver<T, N>)> {
+    endpoints(None)
 }
 
-pub struct Queue<T> 
+/// Like `ipc_queue`, but the endpoints carry eventfds, see
+/// `Sender::as_raw_fd` and `Receiver::as_raw_fd`.
+#[allow(dead_code)]
+pub fn ipc_queue_with_eventfd<T: Copy, const N: usize>() -> nix::Result<(Sender<T, N>, Receiver<T, N>)> {
+    endpoints(Some(EventFds::new()?))
+}
+
+fn endpoints<T: Copy, const N: usize>(eventfds: Option<EventFds>) -> nix::Result<(Sender<T, N>, Receiver<T, N>)> {
+    let shared = Shm::new(Endpoints {
+        queue: Queue::pshared(),
+        senders: AtomicUsize::new(1),
+        receivers: AtomicUsize::new(1)
+    })?;
+    let eventfds = eventfds.map(Arc::new);
+
+    Ok((Sender { shared: shared.clone(), eventfds: eventfds.clone() }, Receiver { shared, eventfds }))
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum EndpointError {
+    /// All endpoints on the other side are gone.
+    Disconnected,
+    Sys(Error)
+}
+
+impl From<Error> for EndpointError {
+    fn from(err: Error) -> Self {
+        EndpointError::Sys(err)
+    }
+}
+
+/// Queue with the number of live senders and receivers, kept next to it in
+/// the segment.
+///
+/// Counts are updated on clone and drop, so an endpoint in a process which
+/// dies without unwinding is never released.
+struct Endpoints<T, const N: usize>
     where T: Copy
 {
-    buffer: Mutex<RingBuffer<T>>,
-    in_cond: Condvar,
-    out_cond: Condvar
+    queue: Queue<T, N>,
+    senders: AtomicUsize,
+    receivers: AtomicUsize
 }
 
-impl<T> PthreadPrimitiveConstructor for Queue<T> 
-    where T: Copy 
+impl<T, const N: usize> Endpoints<T, N>
+    where T: Copy
+{
+    /// Wakes everyone blocked on `cond` or polling `eventfd` after the other
+    /// side disconnected.
+    fn disconnect(&self, cond: &Condvar, eventfd: Option<&OwnedFd>) {
+        // Waiters check the counts under the lock, so once we hold it they
+        // are either waiting or will see the new count.
+        if let Ok(_guard) = self.queue.buffer.lock() {
+            let _ = cond.broadcast();
+            if let Some(eventfd) = eventfd {
+                signal_eventfd(eventfd);
+            }
+        }
+    }
+}
+
+/// Eventfds of a queue created by `ipc_queue_with_eventfd`, so processes
+/// can wait for it with poll or epoll: `data` is readable while the queue
+/// is not empty, and `space` while it is not full. Either also becomes
+/// readable once the other side disconnected.
+///
+/// Descriptors are process-local, so they live in the endpoints rather than
+/// in the segment. Every process holding an endpoint has them by inheriting
+/// it across `fork`, and all copies refer to the same eventfds.
+struct EventFds {
+    data: OwnedFd,
+    space: OwnedFd
+}
+
+impl EventFds {
+    /// Eventfds of an empty queue.
+    fn new() -> Result<Self, Error> {
+        let fds = EventFds { data: new_eventfd()?, space: new_eventfd()? };
+        signal_eventfd(&fds.space);
+        Ok(fds)
+    }
+
+    /// Updates readiness after a value was written to `buffer`.
+    fn wrote<T: Copy, const N: usize>(&self, buffer: &RingBuffer<T, N>) {
+        if buffer.len() == 1 {
+            signal_eventfd(&self.data);
+        }
+        if buffer.is_full() {
+            reset_eventfd(&self.space);
+        }
+    }
+
+    /// Updates readiness after `count` values were read from `buffer`.
+    /// `data` stays readable when empty once all senders are gone.
+    fn read<T: Copy, const N: usize>(&self, buffer: &RingBuffer<T, N>, count: usize, disconnected: bool) {
+        if buffer.len() == 0 && !disconnected {
+            reset_eventfd(&self.data);
+        }
+        if buffer.len() + count == N {
+            signal_eventfd(&self.space);
+        }
+    }
+}
+
+fn new_eventfd() -> Result<OwnedFd, Error> {
+    let fd = unsafe { libc::eventfd(0, libc::EFD_NONBLOCK | libc::EFD_CLOEXEC) };
+    if fd == -1 {
+        Err(Error::Sys(Errno::last()))
+    } else {
+        Ok(unsafe { OwnedFd::from_raw_fd(fd) })
+    }
+}
+
+// Readiness is advisory, and it is only updated once the ring changed, so
+// eventfd failures are ignored rather than failing an operation which
+// already took effect. With values of 0 and 1 they can't fail anyway.
+
+/// Makes `fd` readable.
+fn signal_eventfd(fd: &OwnedFd) {
+    let _ = unistd::write(fd.as_raw_fd(), &1u64.to_ne_bytes());
+}
+
+/// Makes `fd` unreadable.
+fn reset_eventfd(fd: &OwnedFd) {
+    let _ = unistd::read(fd.as_raw_fd(), &mut [0; 8]);
+}
+
+/// Sending half of a queue created by `ipc_queue`.
+///
+/// A sender moved into `process::spawn` belongs to the child, and only
+/// counts as gone once the child dropped it.
+pub struct Sender<T, const N: usize = RING_BUFFER_SIZE>
+    where T: Copy
+{
+    shared: Shm<Endpoints<T, N>>,
+    eventfds: Option<Arc<EventFds>>
+}
+
+#[allow(dead_code)]
+impl<T, const N: usize> Sender<T, N>
+    where T: Copy
+{
+    /// Pushes `value`, blocking while the queue is full.
+    /// Fails with `Disconnected` once all receivers are gone.
+    pub fn push(&self, value: T) -> Result<(), EndpointError> {
+        let queue = &self.shared.queue;
+        {
+            let mut guard = queue.buffer.lock()?;
+            loop {
+                if self.shared.receivers.load(Ordering::Acquire) == 0 {
+                    return Err(EndpointError::Disconnected);
+                }
+                if self.write(&mut guard, value) {
+                    break;
+                }
+                guard = queue.wait_for_space(guard, None)?;
+            }
+        }
+
+        Ok(queue.in_cond.signal()?)
+    }
+
+    /// Pushes `value` unless the queue is full.
+    /// Fails with `Disconnected` once all receivers are gone.
+    pub fn try_push(&self, value: T) -> Result<Result<(), Full<T>>, EndpointError> {
+        let queue = &self.shared.queue;
+        {
+            let mut guard = queue.buffer.lock()?;
+            if self.shared.receivers.load(Ordering::Acquire) == 0 {
+                return Err(EndpointError::Disconnected);
+            }
+            if !self.write(&mut guard, value) {
+                return Ok(Err(Full(value)));
+            }
+        }
+
+        queue.in_cond.signal()?;
+        Ok(Ok(()))
+    }
+
+    pub fn stats(&self) -> Result<QueueStats, Error> {
+        self.shared.queue.stats()
+    }
+
+    /// Eventfd which is readable while the queue is not full, or once all
+    /// receivers are gone, if the queue was created by
+    /// `ipc_queue_with_eventfd`. Only poll it; reading it is up to the queue.
+    pub fn as_raw_fd(&self) -> Option<RawFd> {
+        self.eventfds.as_ref().map(|fds| fds.space.as_raw_fd())
+    }
+
+    /// Writes `value` unless the queue is full, keeping the eventfds in sync.
+    fn write(&self, buffer: &mut RingBuffer<T, N>, value: T) -> bool {
+        if buffer.write(value).is_err() {
+            return false;
+        }
+        if let Some(ref fds) = self.eventfds {
+            fds.wrote(buffer);
+        }
+        true
+    }
+}
+
+impl<T, const N: usize> Clone for Sender<T, N>
+    where T: Copy
+{
+    fn clone(&self) -> Self {
+        self.shared.senders.fetch_add(1, Ordering::AcqRel);
+        Sender { shared: self.shared.clone(), eventfds: self.eventfds.clone() }
+    }
+}
+
+impl<T, const N: usize> Drop for Sender<T, N>
+    where T: Copy
+{
+    fn drop(&mut self) {
+        if process::handing_over() {
+            return;
+        }
+        if self.shared.senders.fetch_sub(1, Ordering::AcqRel) == 1 {
+            self.shared.disconnect(&self.shared.queue.in_cond, self.eventfds.as_ref().map(|fds| &fds.data));
+        }
+    }
+}
+
+/// Receiving half of a queue created by `ipc_queue`. Like a `Sender`, it is
+/// only released by the child if it was moved into `process::spawn`.
+pub struct Receiver<T, const N: usize = RING_BUFFER_SIZE>
+    where T: Copy
+{
+    shared: Shm<Endpoints<T, N>>,
+    eventfds: Option<Arc<EventFds>>
+}
+
+#[allow(dead_code)]
+impl<T, const N: usize> Receiver<T, N>
+    where T: Copy
+{
+    /// Pops a value, blocking while the queue is empty. Fails with
+    /// `Disconnected` once the queue is empty and all senders are gone.
+    pub fn pop(&self) -> Result<T, EndpointError> {
+        match self.pop_until(None)? {
+            Some(value) => Ok(value),
+            None => unreachable!("pop without deadline timed out")
+        }
+    }
+
+    pub fn try_pop(&self) -> Result<Option<T>, EndpointError> {
+        let value = self.read(&mut *self.shared.queue.buffer.lock()?);
+        match value {
+            Some(value) => {
+                self.shared.queue.out_cond.signal()?;
+                Ok(Some(value))
+            },
+            None if self.shared.senders.load(Ordering::Acquire) == 0 => Err(EndpointError::Disconnected),
+            None => Ok(None)
+        }
+    }
+
+    pub fn timed_pop(&self, time: Duration) -> Result<Option<T>, EndpointError> {
+        self.pop_until(Some(Instant::now() + time))
+    }
+
+    pub fn pop_deadline(&self, deadline: Instant) -> Result<Option<T>, EndpointError> {
+        self.pop_until(Some(deadline))
+    }
+
+    pub fn stats(&self) -> Result<QueueStats, Error> {
+        self.shared.queue.stats()
+    }
+
+    /// Eventfd which is readable while the queue is not empty, or once all
+    /// senders are gone, if the queue was created by `ipc_queue_with_eventfd`.
+    /// Only poll it; reading it is up to the queue.
+    pub fn as_raw_fd(&self) -> Option<RawFd> {
+        self.eventfds.as_ref().map(|fds| fds.data.as_raw_fd())
+    }
+
+    /// Iterates over values as they arrive, blocking until all senders are
+    /// gone and the queue is empty.
+    pub fn iter(&self) -> Iter<'_, T, N> {
+        Iter { receiver: self, done: false }
+    }
+
+    /// Iterates over the values which can be popped without blocking.
+    pub fn try_iter(&self) -> TryIter<'_, T, N> {
+        TryIter { receiver: self, done: false }
+    }
+
+    /// Like `iter`, but also stops once no value arrives for `time`.
+    pub fn iter_timeout(&self, time: Duration) -> TimeoutIter<'_, T, N> {
+        TimeoutIter { receiver: self, time, done: false }
+    }
+
+    fn pop_until(&self, deadline: Option<Instant>) -> Result<Option<T>, EndpointError> {
+        let queue = &self.shared.queue;
+        let value = {
+            let mut guard = queue.buffer.lock()?;
+            loop {
+                if let Some(value) = self.read(&mut guard) {
+                    break value;
+                }
+                if self.shared.senders.load(Ordering::Acquire) == 0 {
+                    return Err(EndpointError::Disconnected);
+                }
+                guard = match deadline {
+                    None => queue.in_cond.wait(guard)?,
+                    Some(deadline) => {
+                        if Instant::now() >= deadline {
+                            return Ok(None);
+                        }
+                        match queue.in_cond.wait_until(guard, deadline) {
+                            Err(Error::Sys(Errno::ETIMEDOUT)) => queue.buffer.lock()?,
+                            Err(err) => return Err(err.into()),
+                            Ok(guard) => guard
+                        }
+                    }
+                };
+            }
+        };
+
+        queue.out_cond.signal()?;
+        Ok(Some(value))
+    }
+
+    /// Reads a value if there is one, keeping the eventfds in sync.
+    fn read(&self, buffer: &mut RingBuffer<T, N>) -> Option<T> {
+        let value = buffer.try_read();
+        if let (Some(_), Some(fds)) = (value, self.eventfds.as_ref()) {
+            fds.read(buffer, 1, self.shared.senders.load(Ordering::Acquire) == 0);
+        }
+        value
+    }
+}
+
+/// Turns the result of a pop into the next item of a receiver iterator,
+/// which ends on disconnect, on `None`, or after yielding an error.
+fn next_item<T>(popped: Result<Option<T>, EndpointError>, done: &mut bool) -> Option<Result<T, Error>> {
+    if *done {
+        return None;
+    }
+
+    match popped {
+        Ok(Some(value)) => Some(Ok(value)),
+        Ok(None) | Err(EndpointError::Disconnected) => {
+            *done = true;
+            None
+        },
+        Err(EndpointError::Sys(err)) => {
+            *done = true;
+            Some(Err(err))
+        }
+    }
+}
+
+/// Blocking iterator returned by `Receiver::iter`.
+pub struct Iter<'a, T, const N: usize>
+    where T: Copy
+{
+    receiver: &'a Receiver<T, N>,
+    done: bool
+}
+
+impl<'a, T, const N: usize> Iterator for Iter<'a, T, N>
+    where T: Copy
+{
+    type Item = Result<T, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let popped = if self.done { Ok(None) } else { self.receiver.pop().map(Some) };
+        next_item(popped, &mut self.done)
+    }
+}
+
+/// Non-blocking iterator returned by `Receiver::try_iter`.
+pub struct TryIter<'a, T, const N: usize>
+    where T: Copy
+{
+    receiver: &'a Receiver<T, N>,
+    done: bool
+}
+
+impl<'a, T, const N: usize> Iterator for TryIter<'a, T, N>
+    where T: Copy
+{
+    type Item = Result<T, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let popped = if self.done { Ok(None) } else { self.receiver.try_pop() };
+        next_item(popped, &mut self.done)
+    }
+}
+
+/// Iterator returned by `Receiver::iter_timeout`.
+pub struct TimeoutIter<'a, T, const N: usize>
+    where T: Copy
+{
+    receiver: &'a Receiver<T, N>,
+    time: Duration,
+    done: bool
+}
+
+impl<'a, T, const N: usize> Iterator for TimeoutIter<'a, T, N>
+    where T: Copy
+{
+    type Item = Result<T, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let popped = if self.done { Ok(None) } else { self.receiver.timed_pop(self.time) };
+        next_item(popped, &mut self.done)
+    }
+}
+
+/// Blocking iterator owning its `Receiver`.
+pub struct IntoIter<T, const N: usize>
+    where T: Copy
+{
+    receiver: Receiver<T, N>,
+    done: bool
+}
+
+impl<T, const N: usize> Iterator for IntoIter<T, N>
+    where T: Copy
+{
+    type Item = Result<T, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let popped = if self.done { Ok(None) } else { self.receiver.pop().map(Some) };
+        next_item(popped, &mut self.done)
+    }
+}
+
+impl<T, const N: usize> IntoIterator for Receiver<T, N>
+    where T: Copy
+{
+    type Item = Result<T, Error>;
+    type IntoIter = IntoIter<T, N>;
+
+    fn into_iter(self) -> IntoIter<T, N> {
+        IntoIter { receiver: self, done: false }
+    }
+}
+
+impl<'a, T, const N: usize> IntoIterator for &'a Receiver<T, N>
+    where T: Copy
+{
+    type Item = Result<T, Error>;
+    type IntoIter = Iter<'a, T, N>;
+
+    fn into_iter(self) -> Iter<'a, T, N> {
+        self.iter()
+    }
+}
+
+impl<T, const N: usize> Clone for Receiver<T, N>
+    where T: Copy
+{
+    fn clone(&self) -> Self {
+        self.shared.receivers.fetch_add(1, Ordering::AcqRel);
+        Receiver { shared: self.shared.clone(), eventfds: self.eventfds.clone() }
+    }
+}
+
+impl<T, const N: usize> Drop for Receiver<T, N>
+    where T: Copy
+{
+    fn drop(&mut self) {
+        if process::handing_over() {
+            return;
+        }
+        if self.shared.receivers.fetch_sub(1, Ordering::AcqRel) == 1 {
+            self.shared.disconnect(&self.shared.queue.out_cond, self.eventfds.as_ref().map(|fds| &fds.space));
+        }
+    }
+}
+
+/// Interface shared by the crate's queues, so callers can switch between
+/// implementations.
+#[allow(dead_code)]
+pub trait BlockingQueue<T> {
+    /// Pushes `value`, blocking while the queue is full.
+    fn push(&self, value: T) -> Result<(), Error>;
+
+    /// Pushes `value` if there is space for it right now, handing it back
+    /// otherwise.
+    fn try_push(&self, value: T) -> Result<Result<(), Full<T>>, Error>;
+
+    /// Pushes `value`, blocking for at most `time` while the queue is full.
+    fn timed_push(&self, value: T, time: Duration) -> Result<Result<(), Full<T>>, Error> {
+        self.push_deadline(value, Instant::now() + time)
+    }
+
+    /// Pushes `value`, blocking until `deadline` at most while the queue is full.
+    fn push_deadline(&self, value: T, deadline: Instant) -> Result<Result<(), Full<T>>, Error>;
+
+    /// Pops a value, blocking while the queue is empty.
+    fn pop(&self) -> Result<T, Error>;
+
+    fn try_pop(&self) -> Result<Option<T>, Error>;
+
+    /// Pops a value, blocking for at most `time` while the queue is empty.
+    fn timed_pop(&self, time: Duration) -> Result<Option<T>, Error> {
+        self.pop_deadline(Instant::now() + time)
+    }
+
+    /// Pops a value, blocking until `deadline` at most while the queue is empty.
+    fn pop_deadline(&self, deadline: Instant) -> Result<Option<T>, Error>;
+}
+
+/// How a `Queue` puts producers and consumers to sleep while it is full or
+/// empty.
+pub trait WaitStrategy: PthreadPrimitiveConstructor {
+    /// Unlocks `guard` and sleeps until woken or until `deadline`, failing
+    /// with `ETIMEDOUT` in the latter case. Relocks the mutex unless it
+    /// fails. May return spuriously, so callers have to recheck.
+    fn sleep<'a, U>(&self, guard: MutexGuard<'a, U>, deadline: Option<Instant>) -> Result<MutexGuard<'a, U>, Error>;
+
+    fn wake_one(&self) -> Result<(), Error>;
+
+    fn wake_all(&self) -> Result<(), Error>;
+}
+
+/// Sleeps on a pshared pthread condvar.
+impl WaitStrategy for Condvar {
+    fn sleep<'a, U>(&self, guard: MutexGuard<'a, U>, deadline: Option<Instant>) -> Result<MutexGuard<'a, U>, Error> {
+        match deadline {
+            None => self.wait(guard),
+            Some(deadline) => self.wait_until(guard, deadline)
+        }
+    }
+
+    fn wake_one(&self) -> Result<(), Error> {
+        self.signal()
+    }
+
+    fn wake_all(&self) -> Result<(), Error> {
+        self.broadcast()
+    }
+}
+
+/// Sleeps on a futex sequence word, so wakeups only enter the kernel when
+/// somebody is actually sleeping. Linux only.
+impl WaitStrategy for Event {
+    fn sleep<'a, U>(&self, guard: MutexGuard<'a, U>, deadline: Option<Instant>) -> Result<MutexGuard<'a, U>, Error> {
+        // Wakers change the queue under the lock before notifying, so the
+        // key taken under the lock tells whether we missed a wakeup
+        let key = self.prepare();
+        let mutex = guard.unlock();
+        self.wait(key, deadline)?;
+        mutex.lock()
+    }
+
+    fn wake_one(&self) -> Result<(), Error> {
+        self.notify_one()
+    }
+
+    fn wake_all(&self) -> Result<(), Error> {
+        self.notify_all()
+    }
+}
+
+/// `Queue` sleeping on futexes instead of pthread condvars.
+#[allow(dead_code)]
+pub type FutexQueue<T, const N: usize = RING_BUFFER_SIZE> = Queue<T, N, Event>;
+
+/// Value rejected by a push because the queue stayed full.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct Full<T>(pub T);
+
+/// Blocking FIFO queue holding up to `N` values, which must be a power of two.
+///
+/// Consumers wait on `in_cond` while the queue is empty, and producers wait
+/// on `out_cond` while it is full. Every call which writes a value signals
+/// `in_cond` once per value, and every call which removes one signals
+/// `out_cond` once per value; calls which change nothing signal nothing.
+/// Batch calls, disconnects and the guards of `loan` and `receive`, which
+/// may finish out of order, broadcast instead. Waiters recheck the
+/// buffer after any wakeup, including a timeout, so a signal is never lost
+/// on a waiter which gives up.
+///
+/// `W` is how waiters sleep, see `WaitStrategy`.
+#[repr(C)]
+pub struct Queue<T, const N: usize = RING_BUFFER_SIZE, W = Condvar> 
+    where T: Copy, W: WaitStrategy
+{
+    buffer: Mutex<RingBuffer<T, N>>,
+    in_cond: W,
+    out_cond: W,
+    blocked_producers: AtomicUsize
+}
+
+impl<T, const N: usize, W> PthreadPrimitiveConstructor for Queue<T, N, W> 
+    where T: Copy, W: WaitStrategy
 {
     fn new() -> Self {
         Queue {
             buffer: Mutex::new(RingBuffer::new()),
-            in_cond: Condvar::new(),
-            out_cond: Condvar::new(),
+            in_cond: W::new(),
+            out_cond: W::new(),
+            blocked_producers: AtomicUsize::new(0),
         }
     }
 
     fn pshared() -> Self {
         Queue {
             buffer: Mutex::pshared(RingBuffer::new()),
-            in_cond: Condvar::pshared(),
-            out_cond: Condvar::pshared(),
+            in_cond: W::pshared(),
+            out_cond: W::pshared(),
+            blocked_producers: AtomicUsize::new(0),
         }
     }
 }
 
+unsafe impl<T, const N: usize, W> SharedLayout for Queue<T, N, W>
+    where T: Copy + SharedLayout, W: WaitStrategy + SharedLayout
+{
+    fn layout() -> TypeLayout {
+        TypeLayout::new::<Self>("Queue")
+            .field("buffer", mem::offset_of!(Self, buffer), |s: &Self| &s.buffer)
+            .field("in_cond", mem::offset_of!(Self, in_cond), |s: &Self| &s.in_cond)
+            .field("out_cond", mem::offset_of!(Self, out_cond), |s: &Self| &s.out_cond)
+            .field("blocked_producers", mem::offset_of!(Self, blocked_producers), |s: &Self| &s.blocked_producers)
+    }
+}
+
+unsafe impl<T, const N: usize, W> Checkpoint for Queue<T, N, W>
+    where T: PlainData, W: WaitStrategy + Checkpoint
+{
+    type Guard<'a> = MutexGuard<'a, RingBuffer<T, N>> where T: 'a, W: 'a;
+
+    fn freeze(&self) -> Result<MutexGuard<'_, RingBuffer<T, N>>, Error> {
+        self.buffer.freeze()
+    }
+
+    fn thaw(&mut self) {
+        self.buffer.thaw();
+        self.buffer.get_mut().drop_guards();
+        self.in_cond.thaw();
+        self.out_cond.thaw();
+        // Producers blocked on the original don't wait on the copy
+        *self.blocked_producers.get_mut() = 0;
+    }
+}
+
+/// Lifetime counters of a `Queue`, as returned by `Queue::stats`.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct QueueStats {
+    pub len: usize,
+    pub pushed: u64,
+    pub popped: u64,
+    /// Producers currently waiting for space.
+    pub blocked_producers: usize,
+    /// Largest number of values ever queued at once.
+    pub high_water: usize
+}
+
+#[allow(dead_code)]
+impl<T, const N: usize, W> Queue<T, N, W>
+    where T: Copy, W: WaitStrategy
+{
+    pub fn capacity(&self) -> usize {
+        N
+    }
+
+    pub fn len(&self) -> Result<usize, Error> {
+        Ok(self.buffer.lock()?.len())
+    }
+
+    pub fn is_empty(&self) -> Result<bool, Error> {
+        Ok(self.buffer.lock()?.len() == 0)
+    }
+
+    pub fn is_full(&self) -> Result<bool, Error> {
+        Ok(self.buffer.lock()?.is_full())
+    }
+
+    pub fn stats(&self) -> Result<QueueStats, Error> {
+        let guard = self.buffer.lock()?;
+        Ok(QueueStats {
+            len: guard.len(),
+            pushed: guard.pushed,
+            popped: guard.popped,
+            blocked_producers: self.blocked_producers.load(Ordering::Relaxed),
+            high_water: guard.high_water
+        })
+    }
+
+    /// Loans the next free slot, blocking while the queue is full, so a large
+    /// value can be built in place with `SlotGuard::write_with` and published
+    /// with `SlotGuard::commit`.
+    ///
+    /// Values are received in loan order, so consumers wait for a loaned
+    /// slot once it is the oldest one.
+    pub fn loan(&self) -> Result<SlotGuard<'_, T, N, W>, Error> {
+        let mut guard = self.buffer.lock()?;
+        loop {
+            if let Ok(idx) = guard.reserve() {
+                return Ok(SlotGuard::new(self, &mut guard, idx));
+            }
+            guard = self.wait_for_space(guard, None)?;
+        }
+    }
+
+    /// Like `loan`, but returns `None` if the queue is full.
+    pub fn try_loan(&self) -> Result<Option<SlotGuard<'_, T, N, W>>, Error> {
+        let mut guard = self.buffer.lock()?;
+        match guard.reserve() {
+            Ok(idx) => Ok(Some(SlotGuard::new(self, &mut guard, idx))),
+            Err(_) => Ok(None)
+        }
+    }
+
+    /// Pops the oldest value, blocking while the queue is empty, and lends
+    /// it in place. Its slot is freed when the guard is dropped.
+    pub fn receive(&self) -> Result<ReadGuard<'_, T, N, W>, Error> {
+        let mut guard = self.buffer.lock()?;
+        loop {
+            if let Some(guard) = self.lend(&mut guard)? {
+                return Ok(guard);
+            }
+            guard = self.in_cond.sleep(guard, None)?;
+        }
+    }
+
+    /// Like `receive`, but returns `None` if the queue is empty.
+    pub fn try_receive(&self) -> Result<Option<ReadGuard<'_, T, N, W>>, Error> {
+        self.lend(&mut *self.buffer.lock()?)
+    }
+
+    /// Lends the oldest value of the locked `buffer`, if any, waking
+    /// producers for cancelled slots which were freed along the way.
+    fn lend(&self, buffer: &mut RingBuffer<T, N>) -> Result<Option<ReadGuard<'_, T, N, W>>, Error> {
+        let len = buffer.len();
+        let lent = buffer.lend().map(|idx| ReadGuard::new(self, buffer, idx));
+        self.wake_producers(len - buffer.len())?;
+        Ok(lent)
+    }
+
+    /// Wakes producers after `freed` slots became free, all of them if
+    /// cancelled slots were freed along with a popped one.
+    fn wake_producers(&self, freed: usize) -> Result<(), Error> {
+        match freed {
+            0 => Ok(()),
+            1 => self.out_cond.wake_one(),
+            _ => self.out_cond.wake_all()
+        }
+    }
+
+    /// Waits on `out_cond` until `deadline` at most, counted as a blocked
+    /// producer meanwhile.
+    fn wait_for_space<'a>(&self, guard: MutexGuard<'a, RingBuffer<T, N>>, deadline: Option<Instant>)
+        -> Result<MutexGuard<'a, RingBuffer<T, N>>, Error>
+    {
+        self.blocked_producers.fetch_add(1, Ordering::Relaxed);
+        let guard = self.out_cond.sleep(guard, deadline);
+        self.blocked_producers.fetch_sub(1, Ordering::Relaxed);
+        guard
+    }
+}
+
 use std::fmt::Debug;
 
-impl<T> Queue<T> 
-    where T: Copy + Debug
+impl<T, const N: usize, W> Queue<T, N, W> 
+    where T: Copy + Debug, W: WaitStrategy
 {
     pub fn push(&self, value: T) -> Result<(), Error> {
         let mut guard = self.buffer.lock()?;
         while guard.write(value).is_err() {
-            guard = self.out_cond.wait(guard)?;
+            guard = self.wait_for_space(guard, None)?;
         }
 
-        self.in_cond.signal()
+        self.in_cond.wake_one()
     }
 
-    pub fn pop(&self) -> Result<T, Error> {
-        let poll_result = {
-            let poll_value = || {
-                let mut guard = self.buffer.lock()?;
-                loop {
-                    if let Some(t) = guard.try_read() {
-                        return Ok(t);
-                    } else {
-                        guard = self.in_cond.wait(guard)?;
-                    }
+    /// Pushes `value` if there is space for it right now, handing it back
+    /// otherwise.
+    #[allow(dead_code)]
+    pub fn try_push(&self, value: T) -> Result<Result<(), Full<T>>, Error> {
+        if self.buffer.lock()?.write(value).is_err() {
+            return Ok(Err(Full(value)));
+        }
+
+        self.in_cond.wake_one()?;
+        Ok(Ok(()))
+    }
+
+    /// Pushes `value`, blocking for at most `time` while the queue is full.
+    #[allow(dead_code)]
+    pub fn timed_push(&self, value: T, time: Duration) -> Result<Result<(), Full<T>>, Error> {
+        self.push_deadline(value, Instant::now() + time)
+    }
+
+    /// Pushes `value`, blocking until `deadline` at most while the queue is full.
+    #[allow(dead_code)]
+    pub fn push_deadline(&self, value: T, deadline: Instant) -> Result<Result<(), Full<T>>, Error> {
+        {
+            let mut guard = self.buffer.lock()?;
+            while guard.write(value).is_err() {
+                if Instant::now() >= deadline {
+                    return Ok(Err(Full(value)));
                 }
-            };
-            poll_value()
-        };
+                guard = match self.wait_for_space(guard, Some(deadline)) {
+                    Err(Error::Sys(Errno::ETIMEDOUT)) => self.buffer.lock()?,
+                    Err(err) => return Err(err),
+                    Ok(guard) => guard
+                };
+            }
+        }
 
-        self.out_cond.signal()?;
-        poll_result
-    }  
+        self.in_cond.wake_one()?;
+        Ok(Ok(()))
+    }
+
+    pub fn pop(&self) -> Result<T, Error> {
+        match self.pop_until(None)? {
+            Some(value) => Ok(value),
+            None => unreachable!("pop without deadline timed out")
+        }
+    }
 
     pub fn try_pop(&self) -> Result<Option<T>, Error> {
-        Ok(self.buffer.lock()?.try_read())
+        let (value, freed) = {
+            let mut guard = self.buffer.lock()?;
+            let len = guard.len();
+            let value = guard.try_read();
+            (value, len - guard.len())
+        };
+
+        self.wake_producers(freed)?;
+        Ok(value)
     }
-    
+
     pub fn timed_pop(&self, time: Duration) -> Result<Option<T>, Error> {
-        let poll_result = {
-            let poll_value = || {
-                let mut guard = self.buffer.lock()?;
-                match guard.try_read() {
-                    Some(t) => Ok(Some(t)),
-                    None => match self.in_cond.timed_wait(guard, time) {
-                        Err(Error::Sys(Errno::ETIMEDOUT)) => Ok(None),
-                        Err(err) => Err(err),
-                        Ok(mut guard) => Ok(guard.try_read())
+        self.pop_until(Some(Instant::now() + time))
+    }
+
+    #[allow(dead_code)]
+    pub fn pop_deadline(&self, deadline: Instant) -> Result<Option<T>, Error> {
+        self.pop_until(Some(deadline))
+    }
+
+    fn pop_until(&self, deadline: Option<Instant>) -> Result<Option<T>, Error> {
+        let (value, freed) = {
+            let mut guard = self.buffer.lock()?;
+            loop {
+                let len = guard.len();
+                if let Some(value) = guard.try_read() {
+                    break (value, len - guard.len());
+                }
+                guard = match deadline {
+                    None => self.in_cond.sleep(guard, None)?,
+                    Some(deadline) => {
+                        if Instant::now() >= deadline {
+                            return Ok(None);
+                        }
+                        // Check once more after a timeout, as the signal
+                        // may have been meant for us
+                        match self.in_cond.sleep(guard, Some(deadline)) {
+                            Err(Error::Sys(Errno::ETIMEDOUT)) => self.buffer.lock()?,
+                            Err(err) => return Err(err),
+                            Ok(guard) => guard
+                        }
                     }
+                };
+            }
+        };
+
+        self.wake_producers(freed)?;
+        Ok(Some(value))
+    }
+
+    /// Pushes all of `values` in order, writing as many as fit per lock
+    /// acquisition. All consumers are woken once per acquisition which wrote
+    /// anything, rather than once per value.
+    #[allow(dead_code)]
+    pub fn push_batch(&self, values: &[T]) -> Result<(), Error> {
+        let mut rest = values;
+        let mut guard = self.buffer.lock()?;
+        loop {
+            let before = rest.len();
+            while let Some((&value, tail)) = rest.split_first() {
+                if guard.write(value).is_err() {
+                    break;
                 }
-            };
-            poll_value()
+                rest = tail;
+            }
+
+            if rest.len() < before {
+                self.in_cond.wake_all()?;
+            }
+            if rest.is_empty() {
+                return Ok(());
+            }
+            guard = self.wait_for_space(guard, None)?;
+        }
+    }
+
+    /// Pops up to `values.len()` values into `values` under one lock
+    /// acquisition and returns how many were popped. Blocks while the queue
+    /// is empty, unless `values` is.
+    #[allow(dead_code)]
+    pub fn pop_batch(&self, values: &mut [T]) -> Result<usize, Error> {
+        if values.is_empty() {
+            return Ok(0);
+        }
+
+        let count = {
+            let mut guard = self.buffer.lock()?;
+            loop {
+                let count = guard.read_into(values);
+                if count > 0 {
+                    break count;
+                }
+                guard = self.in_cond.sleep(guard, None)?;
+            }
+        };
+
+        self.out_cond.wake_all()?;
+        Ok(count)
+    }
+
+    /// Pops all values currently in the queue without blocking.
+    #[allow(dead_code)]
+    pub fn drain_available(&self) -> Result<Vec<T>, Error> {
+        let values = {
+            let mut guard = self.buffer.lock()?;
+            let mut values = Vec::new();
+            while let Some(value) = guard.try_read() {
+                values.push(value);
+            }
+            values
         };
 
-        self.out_cond.signal()?;
-        poll_result
+        if !values.is_empty() {
+            self.out_cond.wake_all()?;
+        }
+        Ok(values)
+    }
+}
+
+/// Free slot loaned by `Queue::loan`.
+///
+/// Dropping it without `commit` publishes nothing, and the slot is freed
+/// once the values before it are gone.
+pub struct SlotGuard<'a, T, const N: usize, W>
+    where T: Copy, W: WaitStrategy
+{
+    queue: &'a Queue<T, N, W>,
+    idx: usize,
+    value: *mut MaybeUninit<T>,
+    written: bool
+}
+
+#[allow(dead_code)]
+impl<'a, T, const N: usize, W> SlotGuard<'a, T, N, W>
+    where T: Copy, W: WaitStrategy
+{
+    fn new(queue: &'a Queue<T, N, W>, buffer: &mut RingBuffer<T, N>, idx: usize) -> Self {
+        SlotGuard { queue, idx, value: buffer.value_ptr(idx), written: false }
+    }
+
+    /// Moves `value` into the slot. Large values are better built in place
+    /// with `write_with`, which spares the copy from the caller's stack.
+    pub fn write(&mut self, value: T) -> &mut T {
+        self.written = true;
+        unsafe { (*self.value).write(value) }
+    }
+
+    /// Builds the value in place by handing the uninitialized slot to
+    /// `init`.
+    ///
+    /// # Safety
+    ///
+    /// `init` must initialize the whole value, which `commit` publishes.
+    pub unsafe fn write_with<F>(&mut self, init: F)
+        where F: FnOnce(&mut MaybeUninit<T>)
+    {
+        init(&mut *self.value);
+        self.written = true;
+    }
+
+    /// Publishes the value written to the slot and wakes all consumers, as
+    /// slots may be committed in another order than they were loaned.
+    ///
+    /// # Panics
+    ///
+    /// If nothing was written to the slot.
+    pub fn commit(self) -> Result<(), Error> {
+        assert!(self.written, "commit of a slot which was never written");
+        self.queue.buffer.lock()?.commit(self.idx);
+        let queue = self.queue;
+        mem::forget(self);
+        queue.in_cond.wake_all()
+    }
+}
+
+impl<'a, T, const N: usize, W> Drop for SlotGuard<'a, T, N, W>
+    where T: Copy, W: WaitStrategy
+{
+    fn drop(&mut self) {
+        // Freeing the slot may make values after it the oldest ones
+        if let Ok(mut guard) = self.queue.buffer.lock() {
+            let len = guard.len();
+            guard.cancel(self.idx);
+            if guard.len() < len {
+                drop(guard);
+                let _ = self.queue.in_cond.wake_all();
+                let _ = self.queue.out_cond.wake_all();
+            }
+        }
+    }
+}
+
+/// Value lent by `Queue::receive`, read in place. Dropping it frees the
+/// slot.
+pub struct ReadGuard<'a, T, const N: usize, W>
+    where T: Copy, W: WaitStrategy
+{
+    queue: &'a Queue<T, N, W>,
+    idx: usize,
+    value: *const T
+}
+
+impl<'a, T, const N: usize, W> ReadGuard<'a, T, N, W>
+    where T: Copy, W: WaitStrategy
+{
+    fn new(queue: &'a Queue<T, N, W>, buffer: &mut RingBuffer<T, N>, idx: usize) -> Self {
+        ReadGuard { queue, idx, value: buffer.value_ptr(idx) as *const T }
+    }
+}
+
+impl<'a, T, const N: usize, W> Deref for ReadGuard<'a, T, N, W>
+    where T: Copy, W: WaitStrategy
+{
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.value }
+    }
+}
+
+impl<'a, T, const N: usize, W> Drop for ReadGuard<'a, T, N, W>
+    where T: Copy, W: WaitStrategy
+{
+    fn drop(&mut self) {
+        if let Ok(mut guard) = self.queue.buffer.lock() {
+            guard.release(self.idx);
+            drop(guard);
+            // Slots may be released in another order than they were received
+            let _ = self.queue.out_cond.wake_all();
+        }
+    }
+}
+
+impl<T, const N: usize, W> BlockingQueue<T> for Queue<T, N, W>
+    where T: Copy + Debug, W: WaitStrategy
+{
+    fn push(&self, value: T) -> Result<(), Error> {
+        Queue::push(self, value)
+    }
+
+    fn try_push(&self, value: T) -> Result<Result<(), Full<T>>, Error> {
+        Queue::try_push(self, value)
+    }
+
+    fn timed_push(&self, value: T, time: Duration) -> Result<Result<(), Full<T>>, Error> {
+        Queue::timed_push(self, value, time)
+    }
+
+    fn push_deadline(&self, value: T, deadline: Instant) -> Result<Result<(), Full<T>>, Error> {
+        Queue::push_deadline(self, value, deadline)
+    }
+
+    fn pop(&self) -> Result<T, Error> {
+        Queue::pop(self)
+    }
+
+    fn try_pop(&self) -> Result<Option<T>, Error> {
+        Queue::try_pop(self)
+    }
+
+    fn timed_pop(&self, time: Duration) -> Result<Option<T>, Error> {
+        Queue::timed_pop(self, time)
+    }
+
+    fn pop_deadline(&self, deadline: Instant) -> Result<Option<T>, Error> {
+        Queue::pop_deadline(self, deadline)
     }
 }
 
 #[derive(Copy, Clone, Debug, Eq, PartialEq)]
-enum RingBufferError {
+pub enum RingBufferError {
     Overflow
 }
 
-const RING_BUFFER_SIZE: usize = 8;
+/// Default capacity of a `Queue`.
+pub const RING_BUFFER_SIZE: usize = 8;
 
-#[derive(Debug)]
-struct RingBuffer<T> 
+/// Ring of slots between `read_idx`, the oldest value, and `write_idx`,
+/// the next slot to write.
+///
+/// Slots handed out by `Queue::loan` and `Queue::receive` stay in use while
+/// their guards live, so slots are freed in order only as long as no guard
+/// is involved. Writers wait for the slot at `write_idx` to be free, and
+/// readers for the one at `read_idx` to be full.
+#[repr(C)]
+#[derive(Copy, Clone, Debug)]
+pub struct RingBuffer<T, const N: usize = RING_BUFFER_SIZE> 
     where T: Copy 
 {
     write_idx: RingBufferIdx,
     read_idx:  RingBufferIdx,
-    buffer:    [Option<T>; RING_BUFFER_SIZE]
+    len:       usize,
+    pushed:    u64,
+    popped:    u64,
+    high_water: usize,
+    buffer:    [Slot<T>; N]
 }
 
-impl<T> RingBuffer<T> 
+impl<T, const N: usize> RingBuffer<T, N> 
     where T: Copy
 {
-    pub fn new() -> RingBuffer<T> {
+    const CAPACITY: usize = {
+        assert!(N.is_power_of_two(), "queue capacity must be a power of two");
+        N
+    };
+
+    pub fn new() -> RingBuffer<T, N> {
         RingBuffer {
-            write_idx: RingBufferIdx::new(0, RING_BUFFER_SIZE),
-            read_idx: RingBufferIdx::new(0, RING_BUFFER_SIZE),
-            buffer: [None; RING_BUFFER_SIZE]
+            write_idx: RingBufferIdx::new(0, Self::CAPACITY),
+            read_idx: RingBufferIdx::new(0, Self::CAPACITY),
+            len: 0,
+            pushed: 0,
+            popped: 0,
+            high_water: 0,
+            buffer: [Slot::EMPTY; N]
         }
     }
 
     pub fn try_read(&mut self) -> Option<T> {
-        let current = self.buffer[self.read_idx.get()].take();
+        let current = self.buffer[self.read_idx.get()].value().copied();
         if current.is_some() {
-            self.read_idx.forward();
+            self.buffer[self.read_idx.get()].state = SlotState::Empty;
+            self.len -= 1;
+            self.advance_read();
         }
         current
     }
 
+    /// Slots in use, including loaned and received ones.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.buffer[self.write_idx.get()].state != SlotState::Empty
+    }
+
+    /// Reads values into `values` until it is full or the buffer is empty,
+    /// returning how many were read.
+    pub fn read_into(&mut self, values: &mut [T]) -> usize {
+        let mut count = 0;
+        for slot in values.iter_mut() {
+            match self.try_read() {
+                Some(value) => *slot = value,
+                None => break
+            }
+            count += 1;
+        }
+        count
+    }
+
     pub fn write(&mut self, value: T) -> Result<(), RingBufferError> {
-        let current = &mut self.buffer[self.write_idx.get()];
-        if current.is_none() {
-            *current = Some(value);
-            self.write_idx.forward();
-            Ok(())
+        let idx = self.reserve()?;
+        self.buffer[idx].value = MaybeUninit::new(value);
+        self.commit(idx);
+        Ok(())
+    }
+
+    /// Reserves the slot at `write_idx` for a `SlotGuard` and returns its
+    /// index.
+    fn reserve(&mut self) -> Result<usize, RingBufferError> {
+        let idx = self.write_idx.get();
+        if self.buffer[idx].state != SlotState::Empty {
+            return Err(RingBufferError::Overflow);
+        }
+
+        self.buffer[idx].state = SlotState::Loaned;
+        self.write_idx.forward();
+        self.len += 1;
+        self.high_water = self.high_water.max(self.len);
+        Ok(idx)
+    }
+
+    /// Publishes the reserved slot `idx` to readers.
+    fn commit(&mut self, idx: usize) {
+        self.buffer[idx].state = SlotState::Full;
+        self.pushed += 1;
+    }
+
+    /// Gives up the reserved slot `idx`, which is freed once it is the
+    /// oldest slot.
+    fn cancel(&mut self, idx: usize) {
+        self.buffer[idx].state = SlotState::Cancelled;
+        self.reclaim();
+    }
+
+    /// Pops the oldest value for a `ReadGuard`, keeping its slot in use
+    /// until `release`, and returns its index.
+    fn lend(&mut self) -> Option<usize> {
+        let idx = self.read_idx.get();
+        if self.buffer[idx].state != SlotState::Full {
+            return None;
+        }
+
+        self.buffer[idx].state = SlotState::Reading;
+        self.advance_read();
+        Some(idx)
+    }
+
+    /// Frees the slot `idx` lent by `lend`.
+    fn release(&mut self, idx: usize) {
+        self.buffer[idx].state = SlotState::Empty;
+        self.len -= 1;
+    }
+
+    /// Gives up the slots of all guards, which are process-local and thus
+    /// gone in a restored or snapshotted copy of the buffer.
+    fn drop_guards(&mut self) {
+        for idx in 0..N {
+            match self.buffer[idx].state {
+                SlotState::Loaned => self.buffer[idx].state = SlotState::Cancelled,
+                SlotState::Reading => self.release(idx),
+                _ => ()
+            }
+        }
+        self.reclaim();
+    }
+
+    /// Moves past the oldest slot once it was popped.
+    fn advance_read(&mut self) {
+        self.read_idx.forward();
+        self.popped += 1;
+        self.reclaim();
+    }
+
+    /// Frees cancelled slots which became the oldest ones, so `read_idx`
+    /// never rests on one.
+    fn reclaim(&mut self) {
+        while self.buffer[self.read_idx.get()].state == SlotState::Cancelled {
+            self.buffer[self.read_idx.get()].state = SlotState::Empty;
+            self.read_idx.forward();
+            self.len -= 1;
+        }
+    }
+
+    /// Pointer to the value of slot `idx`, for guards which access it
+    /// without holding the lock. The slot's state keeps everybody else
+    /// away from it meanwhile.
+    fn value_ptr(&mut self, idx: usize) -> *mut MaybeUninit<T> {
+        &mut self.buffer[idx].value
+    }
+}
+
+unsafe impl<T, const N: usize> SharedLayout for RingBuffer<T, N>
+    where T: Copy + SharedLayout
+{
+    fn layout() -> TypeLayout {
+        TypeLayout::new::<Self>("RingBuffer")
+            .field("write_idx", mem::offset_of!(Self, write_idx), |s: &Self| &s.write_idx)
+            .field("read_idx", mem::offset_of!(Self, read_idx), |s: &Self| &s.read_idx)
+            .field("len", mem::offset_of!(Self, len), |s: &Self| &s.len)
+            .field("pushed", mem::offset_of!(Self, pushed), |s: &Self| &s.pushed)
+            .field("popped", mem::offset_of!(Self, popped), |s: &Self| &s.popped)
+            .field("high_water", mem::offset_of!(Self, high_water), |s: &Self| &s.high_water)
+            .field("buffer", mem::offset_of!(Self, buffer), |s: &Self| &s.buffer)
+    }
+}
+
+unsafe impl<T: PlainData, const N: usize> PlainData for RingBuffer<T, N> {}
+
+/// State of a `RingBuffer` slot.
+#[repr(u8)]
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+enum SlotState {
+    Empty,
+    /// Reserved by a `SlotGuard`, which has not committed yet.
+    Loaned,
+    Full,
+    /// Popped by `Queue::receive`, but still read through a `ReadGuard`.
+    Reading,
+    /// Given up by a `SlotGuard` dropped without commit.
+    Cancelled
+}
+
+unsafe impl SharedLayout for SlotState {
+    fn layout() -> TypeLayout {
+        TypeLayout::new::<Self>("SlotState")
+    }
+}
+
+unsafe impl PlainData for SlotState {}
+
+#[repr(C)]
+#[derive(Copy, Clone)]
+struct Slot<T: Copy> {
+    state: SlotState,
+    value: MaybeUninit<T>
+}
+
+impl<T: Copy> Slot<T> {
+    const EMPTY: Self = Slot { state: SlotState::Empty, value: MaybeUninit::uninit() };
+
+    /// The value, if the slot is full.
+    fn value(&self) -> Option<&T> {
+        if self.state == SlotState::Full {
+            Some(unsafe { self.value.assume_init_ref() })
         } else {
-            Err(RingBufferError::Overflow)
+            None
+        }
+    }
+}
+
+impl<T: Copy + Debug> Debug for Slot<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.value() {
+            Some(value) => value.fmt(f),
+            None => self.state.fmt(f)
         }
     }
 }
 
+unsafe impl<T: Copy + SharedLayout> SharedLayout for Slot<T> {
+    fn layout() -> TypeLayout {
+        TypeLayout::new::<Self>("Slot")
+            .field("state", mem::offset_of!(Self, state), |s: &Self| &s.state)
+            .field("value", mem::offset_of!(Self, value), |s: &Self| &s.value)
+    }
+}
+
+unsafe impl<T: PlainData> PlainData for Slot<T> {}
+
+#[repr(C)]
 #[derive(Copy, Clone, Debug)]
 struct RingBufferIdx {
     bufsize: usize,
@@ -156,7 +1381,9 @@ struct RingBufferIdx {
 }
 
 impl RingBufferIdx {
+    /// `bufsize` must be a power of two, so wrapping is a mask.
     pub fn new(start_idx: usize, bufsize: usize) -> RingBufferIdx {
+        debug_assert!(bufsize.is_power_of_two());
         RingBufferIdx {
             bufsize: bufsize,
             idx: start_idx
@@ -165,7 +1392,7 @@ impl RingBufferIdx {
 
     pub fn forward(&mut self) -> &mut Self {
         self.idx += 1;
-        self.idx %= self.bufsize;
+        self.idx &= self.bufsize - 1;
         self
     } 
 
@@ -174,6 +1401,8 @@ impl RingBufferIdx {
     }
 }
 
+shared_layout!(RingBufferIdx { bufsize, idx });
+
 #[cfg(test)]
 mod tests {
     mod ring_buffer_idx {
@@ -186,9 +1415,46 @@ mod tests {
                 assert_eq!(next_idx, rb_idx.forward().get());
             };
 
-            test_wrapping(0, 10, 1);
+            test_wrapping(0, 16, 1);
             test_wrapping(0, 1,  0);
-            test_wrapping(9, 10, 0);
+            test_wrapping(15, 16, 0);
+        }
+    }
+
+    mod layout {
+        use super::super::{Queue, RingBufferIdx};
+        use ::layout::{SharedLayout, TypeLayout, FieldLayout};
+
+        #[test]
+        fn queue() {
+            let layout = Queue::<i32>::layout();
+            assert_eq!(Ok(()), layout.check(&Queue::<i32>::layout()));
+            assert_eq!(vec!["buffer", "in_cond", "out_cond", "blocked_producers"],
+                       layout.fields.iter().map(|f| &f.name[..]).collect::<Vec<_>>());
+            assert!(layout.digest() != Queue::<u32>::layout().digest());
+        }
+
+        #[test]
+        fn ring_buffer_idx_32bit() {
+            let word = TypeLayout { size: 4, align: 4, ..usize::layout() };
+            let narrow = TypeLayout {
+                name: "RingBufferIdx".into(),
+                size: 8,
+                align: 4,
+                fields: vec![
+                    FieldLayout { name: "bufsize".into(), offset: 0, layout: word.clone() },
+                    FieldLayout { name: "idx".into(), offset: 4, layout: word },
+                ]
+            };
+
+            let native = RingBufferIdx::layout();
+            if cfg!(target_pointer_width = "64") {
+                let err = narrow.check(&native).unwrap_err();
+                assert_eq!(("RingBufferIdx", "size", "16", "8"), (&err.path[..], err.what, &err.expected[..], &err.found[..]));
+                assert!(narrow.digest() != native.digest());
+            } else {
+                assert_eq!(Ok(()), narrow.check(&native));
+            }
         }
     }
 
@@ -199,7 +1465,7 @@ mod tests {
 
         #[test]
         fn rw() {
-            let mut rb = RingBuffer::new();
+            let mut rb: RingBuffer<_> = RingBuffer::new();
             assert_eq!(None,    rb.try_read());
             assert_eq!(Ok(()),  rb.write(1));
             assert_eq!(Some(1), rb.try_read());
@@ -215,16 +1481,26 @@ mod tests {
 
         #[test]
         fn overflow() {
-            let mut rb = RingBuffer::new();
+            let mut rb: RingBuffer<_> = RingBuffer::new();
             for i in 0..RING_BUFFER_SIZE {
                 rb.write(i).unwrap();
             }
             assert_eq!(Err(RingBufferError::Overflow), rb.write(RING_BUFFER_SIZE));
         }
 
+        #[test]
+        fn overflow_custom_capacity() {
+            let mut rb: RingBuffer<_, 2> = RingBuffer::new();
+            assert_eq!(Ok(()), rb.write(1));
+            assert_eq!(Ok(()), rb.write(2));
+            assert_eq!(Err(RingBufferError::Overflow), rb.write(3));
+            assert_eq!(Some(1), rb.try_read());
+            assert_eq!(Ok(()), rb.write(3));
+        }
+
         #[test]
         fn overflow_escape() {
-            let mut rb = RingBuffer::new();
+            let mut rb: RingBuffer<_> = RingBuffer::new();
             for i in 0..RING_BUFFER_SIZE {
                 rb.write(i).unwrap();
             }
@@ -232,20 +1508,24 @@ mod tests {
 
             assert_eq!(Some(0), rb.try_read());
             assert_eq!(Ok(()), rb.write(RING_BUFFER_SIZE));
-            assert_eq!(Some(RING_BUFFER_SIZE), rb.buffer[0]);
+            assert_eq!(Some(RING_BUFFER_SIZE), rb.buffer[0].value().copied());
         }
     }
 
     mod queue {
         use ::pthread::PthreadPrimitiveConstructor;
-        use super::super::Queue;
+        use super::super::{Full, Queue, QueueStats};
+        use std::ptr;
+        use std::sync::Arc;
+        use std::sync::atomic::{AtomicBool, Ordering};
         use std::thread;
+        use std::time::{Duration, Instant};
         use ::process;
         use ::shm::Shm;
 
         #[test]
         fn mpsc() {
-            let queue = Shm::new(Queue::new())
+            let queue: Shm<Queue<_>> = Shm::new(Queue::new())
                 .unwrap();
                     
             let producer = {
@@ -268,7 +1548,7 @@ mod tests {
 
         #[test]
         fn mpsc_ipc() {
-            let queue = Shm::new(Queue::pshared())
+            let queue: Shm<Queue<_>> = Shm::new(Queue::pshared())
                 .unwrap();
             
             {
@@ -287,18 +1567,512 @@ mod tests {
             }
         }
 
+        #[test]
+        fn capacity() {
+            let queue: Queue<i32> = Queue::new();
+            assert_eq!(8, queue.capacity());
+
+            let queue: Queue<i32, 64> = Queue::new();
+            assert_eq!(64, queue.capacity());
+            for i in 0..64 {
+                queue.push(i).unwrap();
+            }
+            for i in 0..64 {
+                assert_eq!(Ok(Some(i)), queue.try_pop());
+            }
+            assert_eq!(Ok(None), queue.try_pop());
+        }
+
         #[test]
         fn try_pop() {
-            let queue = Queue::new();
+            let queue: Queue<_> = Queue::new();
             assert_eq!(Ok(None), queue.try_pop());
             queue.push(1).unwrap();
             assert_eq!(Ok(Some(1)), queue.try_pop());
         }
+
+        #[test]
+        fn len() {
+            let queue: Queue<i32, 2> = Queue::new();
+            assert_eq!((Ok(0), Ok(true), Ok(false)), (queue.len(), queue.is_empty(), queue.is_full()));
+            queue.push(1).unwrap();
+            assert_eq!((Ok(1), Ok(false), Ok(false)), (queue.len(), queue.is_empty(), queue.is_full()));
+            queue.push(2).unwrap();
+            assert_eq!((Ok(2), Ok(false), Ok(true)), (queue.len(), queue.is_empty(), queue.is_full()));
+            queue.pop().unwrap();
+            assert_eq!(Ok(1), queue.len());
+        }
+
+        #[test]
+        fn stats() {
+            let queue: Shm<Queue<i32, 4>> = Shm::new(Queue::new()).unwrap();
+            queue.push_batch(&[1, 2, 3]).unwrap();
+            queue.pop().unwrap();
+            queue.push_batch(&[4, 5]).unwrap();
+
+            let producer = {
+                let queue = queue.clone();
+                thread::spawn(move || queue.push(6))
+            };
+            while queue.stats().unwrap().blocked_producers == 0 {
+                thread::yield_now();
+            }
+            assert_eq!(Ok(QueueStats { len: 4, pushed: 5, popped: 1, blocked_producers: 1, high_water: 4 }),
+                       queue.stats());
+
+            assert_eq!(Ok(vec![2, 3, 4, 5]), queue.drain_available());
+            producer.join().unwrap().unwrap();
+            assert_eq!(Ok(QueueStats { len: 1, pushed: 6, popped: 5, blocked_producers: 0, high_water: 4 }),
+                       queue.stats());
+        }
+
+        #[test]
+        fn blocked_producers_in_snapshot() {
+            let queue: Shm<Queue<i32, 2>> = Shm::new(Queue::pshared()).unwrap();
+            queue.push_batch(&[1, 2]).unwrap();
+
+            let producer = {
+                let queue = queue.clone();
+                thread::spawn(move || queue.push(3))
+            };
+            while queue.stats().unwrap().blocked_producers == 0 {
+                thread::yield_now();
+            }
+
+            let snapshot = queue.snapshot().unwrap();
+            assert_eq!(0, snapshot.stats().unwrap().blocked_producers);
+            assert_eq!(1, queue.stats().unwrap().blocked_producers);
+
+            assert_eq!(Ok(1), queue.pop());
+            producer.join().unwrap().unwrap();
+        }
+
+        #[test]
+        fn spurious_wakeups() {
+            let queue: Shm<Queue<i32>> = Shm::new(Queue::new()).unwrap();
+            let done = Arc::new(AtomicBool::new(false));
+            let waker = {
+                let queue = queue.clone();
+                let done = done.clone();
+                thread::spawn(move || {
+                    while !done.load(Ordering::Relaxed) {
+                        queue.in_cond.broadcast().unwrap();
+                        queue.out_cond.broadcast().unwrap();
+                        thread::yield_now();
+                    }
+                })
+            };
+
+            let start = Instant::now();
+            assert_eq!(Ok(None), queue.timed_pop(Duration::from_millis(50)));
+            assert!(start.elapsed() >= Duration::from_millis(50));
+
+            for i in 0..8 {
+                queue.push(i).unwrap();
+            }
+            let deadline = Instant::now() + Duration::from_millis(50);
+            assert_eq!(Ok(Err(Full(8))), queue.push_deadline(8, deadline));
+            assert!(Instant::now() >= deadline);
+
+            done.store(true, Ordering::Relaxed);
+            waker.join().unwrap();
+        }
+
+        #[test]
+        fn try_push() {
+            let queue: Queue<i32, 2> = Queue::new();
+            assert_eq!(Ok(Ok(())), queue.try_push(1));
+            assert_eq!(Ok(Ok(())), queue.try_push(2));
+            assert_eq!(Ok(Err(Full(3))), queue.try_push(3));
+            assert_eq!(Ok(1), queue.pop());
+            assert_eq!(Ok(Ok(())), queue.try_push(3));
+        }
+
+        #[test]
+        fn timed_push() {
+            let queue: Shm<Queue<i32, 2>> = Shm::new(Queue::new()).unwrap();
+            queue.push(1).unwrap();
+            queue.push(2).unwrap();
+
+            let start = Instant::now();
+            assert_eq!(Ok(Err(Full(3))), queue.timed_push(3, Duration::from_millis(10)));
+            assert!(start.elapsed() >= Duration::from_millis(10));
+
+            let consumer = {
+                let queue = queue.clone();
+                thread::spawn(move || {
+                    thread::sleep(Duration::from_millis(10));
+                    queue.pop()
+                })
+            };
+            assert_eq!(Ok(Ok(())), queue.timed_push(3, Duration::from_secs(10)));
+            assert_eq!(Ok(1), consumer.join().unwrap());
+        }
+
+        #[test]
+        fn batch() {
+            let queue: Queue<i32, 4> = Queue::new();
+            queue.push_batch(&[1, 2, 3]).unwrap();
+            let mut values = [0; 2];
+            assert_eq!(Ok(2), queue.pop_batch(&mut values));
+            assert_eq!([1, 2], values);
+            assert_eq!(Ok(0), queue.pop_batch(&mut []));
+            queue.push_batch(&[4, 5]).unwrap();
+            assert_eq!(Ok(vec![3, 4, 5]), queue.drain_available());
+            assert_eq!(Ok(vec![]), queue.drain_available());
+        }
+
+        #[test]
+        fn batch_ipc() {
+            let queue: Shm<Queue<u32, 16>> = Shm::new(Queue::pshared())
+                .unwrap();
+
+            {
+                let queue = queue.clone();
+                process::spawn(move || {
+                    let values = (0..10000).collect::<Vec<_>>();
+                    for chunk in values.chunks(100) {
+                        queue.push_batch(chunk).unwrap();
+                    }
+                }).unwrap();
+            }
+
+            let mut received = Vec::new();
+            let mut values = [0; 7];
+            while received.len() < 10000 {
+                let count = queue.pop_batch(&mut values).unwrap();
+                received.extend_from_slice(&values[..count]);
+            }
+            assert_eq!((0..10000).collect::<Vec<_>>(), received);
+        }
+
+        #[test]
+        fn loan_receive() {
+            let queue: Queue<[u64; 512], 2> = Queue::new();
+
+            let mut slot = queue.try_loan().unwrap().unwrap();
+            let mut loaned = ptr::null();
+            unsafe {
+                slot.write_with(|slot| {
+                    let values = slot.as_mut_ptr() as *mut u64;
+                    for i in 0..512 {
+                        values.add(i).write(i as u64);
+                    }
+                    loaned = slot.as_ptr();
+                });
+            }
+            assert!(queue.try_receive().unwrap().is_none());
+            slot.commit().unwrap();
+
+            let value = queue.try_receive().unwrap().unwrap();
+            assert_eq!(loaned, &*value as *const _);
+            assert!(value.iter().enumerate().all(|(i, &v)| v == i as u64));
+            assert_eq!(Ok(1), queue.len());
+            drop(value);
+            assert_eq!(Ok(0), queue.len());
+            assert!(queue.try_receive().unwrap().is_none());
+        }
+
+        #[test]
+        fn uncommitted_loan() {
+            let queue: Queue<i32, 2> = Queue::new();
+            let mut slot = queue.loan().unwrap();
+            slot.write(1);
+            queue.push(2).unwrap();
+            assert_eq!(Ok(Err(Full(3))), queue.try_push(3));
+
+            // Values after a loaned slot wait for it
+            assert_eq!(Ok(None), queue.try_pop());
+            drop(slot);
+            assert_eq!(Ok(Some(2)), queue.try_pop());
+
+            // Both slots are free again
+            queue.push(3).unwrap();
+            queue.push(4).unwrap();
+            assert_eq!(Ok(vec![3, 4]), queue.drain_available());
+            assert_eq!(Ok(QueueStats { len: 0, pushed: 3, popped: 3, blocked_producers: 0, high_water: 2 }),
+                       queue.stats());
+        }
+
+        #[test]
+        fn out_of_order_release() {
+            let queue: Queue<i32, 2> = Queue::new();
+            queue.push_batch(&[1, 2]).unwrap();
+            let first = queue.receive().unwrap();
+            let second = queue.receive().unwrap();
+            assert_eq!((1, 2), (*first, *second));
+
+            // The next write goes to the slot which is still read
+            drop(second);
+            assert_eq!(Ok(Err(Full(3))), queue.try_push(3));
+            drop(first);
+            assert_eq!(Ok(Ok(())), queue.try_push(3));
+            assert_eq!(Ok(Some(3)), queue.try_pop());
+        }
+
+        #[test]
+        fn guards_in_snapshot() {
+            let queue: Shm<Queue<i32, 4>> = Shm::new(Queue::pshared()).unwrap();
+            queue.push(1).unwrap();
+            let _slot = queue.loan().unwrap();
+            queue.push(2).unwrap();
+            let _value = queue.receive().unwrap();
+
+            // Guards don't reach the copy, so their slots are given up there
+            let snapshot = queue.snapshot().unwrap();
+            assert_eq!(Ok(Some(2)), snapshot.try_pop());
+            assert_eq!(Ok(0), snapshot.len());
+            assert_eq!(Ok(None), queue.try_pop());
+        }
+
+        #[derive(Copy, Clone)]
+        struct Frame {
+            seq: u64,
+            pixels: [u8; 4096]
+        }
+
+        #[test]
+        fn loan_ipc() {
+            let queue: Shm<Queue<Frame, 4>> = Shm::new(Queue::pshared()).unwrap();
+
+            {
+                let queue = queue.clone();
+                process::spawn(move || {
+                    for i in 0..1000u64 {
+                        let mut slot = queue.loan().unwrap();
+                        unsafe {
+                            slot.write_with(|frame| {
+                                let frame = frame.as_mut_ptr();
+                                ptr::addr_of_mut!((*frame).seq).write(i);
+                                let pixels = ptr::addr_of_mut!((*frame).pixels) as *mut u8;
+                                for p in 0..4096 {
+                                    pixels.add(p).write((i as usize + p) as u8);
+                                }
+                            });
+                        }
+                        slot.commit().unwrap();
+                    }
+                }).unwrap();
+            }
+
+            for i in 0..1000u64 {
+                let frame = queue.receive().unwrap();
+                assert_eq!(i, frame.seq);
+                assert!(frame.pixels.iter().enumerate().all(|(p, &v)| v == (i as usize + p) as u8));
+            }
+        }
+    }
+
+    mod stress {
+        use super::super::{FutexQueue, Queue, WaitStrategy};
+        use ::pthread::PthreadPrimitiveConstructor;
+        use ::futex::Event;
+        use ::pthread::Condvar;
+        use ::process::{self, Process, WaitStatus, WNOHANG};
+        use ::shm::Shm;
+        use std::process::exit;
+        use std::sync::atomic::{AtomicU64, Ordering};
+        use std::thread;
+        use std::time::{Duration, Instant};
+
+        const PROCESSES: u64 = 8;
+        const VALUES: u64 = 2000;
+
+        /// Waits for all children, failing if any of them failed or if they
+        /// did not finish in time, which would mean a lost wakeup. Kills
+        /// the remaining children on failure.
+        fn wait_all(children: Vec<Process>) {
+            let deadline = Instant::now() + Duration::from_secs(60);
+            let mut failure = None;
+            for child in &children {
+                loop {
+                    match child.wait(Some(WNOHANG)).unwrap() {
+                        WaitStatus::StillAlive if failure.is_some() => {
+                            let _ = child.sendsig(process::Signal::SIGKILL);
+                        },
+                        WaitStatus::StillAlive if Instant::now() >= deadline => {
+                            failure = Some(format!("process {} hung", child.pid()));
+                        },
+                        WaitStatus::StillAlive => thread::sleep(Duration::from_millis(1)),
+                        WaitStatus::Exited(_, 0) => break,
+                        other => {
+                            failure = failure.or(Some(format!("process {} failed: {:?}", child.pid(), other)));
+                            break;
+                        }
+                    }
+                }
+            }
+
+            if let Some(failure) = failure {
+                panic!("{}", failure);
+            }
+        }
+
+        fn push<W: WaitStrategy>(queue: &Queue<u64, 2, W>, p: u64, value: u64) -> bool {
+            match p % 3 {
+                0 => queue.push(value).is_ok(),
+                1 => loop {
+                    match queue.timed_push(value, Duration::from_millis(1)) {
+                        Ok(Ok(())) => break true,
+                        Ok(Err(_)) => (),
+                        Err(_) => break false
+                    }
+                },
+                _ => loop {
+                    match queue.try_push(value) {
+                        Ok(Ok(())) => break true,
+                        Ok(Err(_)) => thread::yield_now(),
+                        Err(_) => break false
+                    }
+                }
+            }
+        }
+
+        fn pop<W: WaitStrategy>(queue: &Queue<u64, 2, W>, c: u64) -> Option<u64> {
+            match c % 3 {
+                0 => queue.pop().ok(),
+                1 => loop {
+                    match queue.timed_pop(Duration::from_millis(1)) {
+                        Ok(Some(value)) => break Some(value),
+                        Ok(None) => (),
+                        Err(_) => break None
+                    }
+                },
+                _ => loop {
+                    match queue.try_pop() {
+                        Ok(Some(value)) => break Some(value),
+                        Ok(None) => thread::yield_now(),
+                        Err(_) => break None
+                    }
+                }
+            }
+        }
+
+        fn producers_consumers<W: WaitStrategy>() {
+            let queue: Shm<Queue<u64, 2, W>> = Shm::new(Queue::pshared()).unwrap();
+            let sum: Shm<AtomicU64> = Shm::new(AtomicU64::new(0)).unwrap();
+
+            let mut children = Vec::new();
+            for p in 0..PROCESSES {
+                let queue = queue.clone();
+                children.push(process::spawn(move || {
+                    if !(0..VALUES).all(|i| push(&queue, p, p * VALUES + i)) {
+                        exit(1);
+                    }
+                }).unwrap());
+            }
+            for c in 0..PROCESSES {
+                let queue = queue.clone();
+                let sum = sum.clone();
+                children.push(process::spawn(move || {
+                    for _ in 0..VALUES {
+                        match pop(&queue, c) {
+                            Some(value) => sum.fetch_add(value, Ordering::Relaxed),
+                            None => exit(1)
+                        };
+                    }
+                }).unwrap());
+            }
+
+            wait_all(children);
+            assert_eq!((0..PROCESSES * VALUES).sum::<u64>(), sum.load(Ordering::Relaxed));
+            let stats = queue.stats().unwrap();
+            assert_eq!((0, PROCESSES * VALUES, PROCESSES * VALUES, 0),
+                       (stats.len, stats.pushed, stats.popped, stats.blocked_producers));
+        }
+
+        /// Pairs each kind of producer with each kind of consumer on its own
+        /// queue, so no other kind of call can make up for a missing wakeup.
+        fn every_pairing<W: WaitStrategy>() {
+            let mut children = Vec::new();
+            for p in 0..3 {
+                for c in 0..3 {
+                    let queue: Shm<Queue<u64, 2, W>> = Shm::new(Queue::pshared()).unwrap();
+                    for _ in 0..2 {
+                        let producer = queue.clone();
+                        children.push(process::spawn(move || {
+                            if !(0..VALUES).all(|i| push(&producer, p, i)) {
+                                exit(1);
+                            }
+                        }).unwrap());
+                        let consumer = queue.clone();
+                        children.push(process::spawn(move || {
+                            if !(0..VALUES).all(|_| pop(&consumer, c).is_some()) {
+                                exit(1);
+                            }
+                        }).unwrap());
+                    }
+                }
+            }
+
+            wait_all(children);
+        }
+
+        #[test]
+        fn producers_consumers_ipc() {
+            producers_consumers::<Condvar>();
+        }
+
+        #[test]
+        fn futex_producers_consumers_ipc() {
+            producers_consumers::<Event>();
+        }
+
+        #[test]
+        fn every_pairing_ipc() {
+            every_pairing::<Condvar>();
+        }
+
+        #[test]
+        fn futex_every_pairing_ipc() {
+            every_pairing::<Event>();
+        }
+
+        #[test]
+        fn batches_ipc() {
+            let queue: Shm<FutexQueue<u64, 4>> = Shm::new(Queue::pshared()).unwrap();
+            let sum: Shm<AtomicU64> = Shm::new(AtomicU64::new(0)).unwrap();
+
+            let mut children = Vec::new();
+            for p in 0..PROCESSES {
+                let queue = queue.clone();
+                children.push(process::spawn(move || {
+                    let values = (p * VALUES..(p + 1) * VALUES).collect::<Vec<_>>();
+                    for chunk in values.chunks(1 + p as usize) {
+                        if queue.push_batch(chunk).is_err() {
+                            exit(1);
+                        }
+                    }
+                }).unwrap());
+            }
+            for c in 0..PROCESSES {
+                let queue = queue.clone();
+                let sum = sum.clone();
+                children.push(process::spawn(move || {
+                    let mut values = vec![0; 1 + c as usize];
+                    let mut remaining = VALUES as usize;
+                    while remaining > 0 {
+                        let len = values.len().min(remaining);
+                        match queue.pop_batch(&mut values[..len]) {
+                            Ok(count) => {
+                                sum.fetch_add(values[..count].iter().sum(), Ordering::Relaxed);
+                                remaining -= count;
+                            },
+                            Err(_) => exit(1)
+                        }
+                    }
+                }).unwrap());
+            }
+
+            wait_all(children);
+            assert_eq!((0..PROCESSES * VALUES).sum::<u64>(), sum.load(Ordering::Relaxed));
+            assert_eq!(Ok(true), queue.is_empty());
+        }
     }
 
     #[test]
     fn ipc_queue() {
-        let (tx, rx) = super::ipc_queue().unwrap();
+        let (tx, rx) = super::ipc_queue::<_, 8>().unwrap();
 
         ::process::spawn(move || {
             for i in 0..1000 {
@@ -311,4 +2085,233 @@ mod tests {
             assert_eq!(Ok(i), v);
         }
     }
-}
\ No newline at end of file
+
+    mod endpoints {
+        use super::super::{ipc_queue, ipc_queue_with_eventfd, EndpointError, Full};
+        use ::process;
+        use nix::poll::{poll, PollFd, EventFlags, POLLIN};
+        use std::os::unix::io::RawFd;
+        use std::thread;
+        use std::time::{Duration, Instant};
+
+        #[test]
+        fn sender_handed_over() {
+            let (tx, rx) = ipc_queue::<_, 8>().unwrap();
+            {
+                let tx = tx.clone();
+                process::spawn(move || tx.push(1).unwrap()).unwrap().wait(None).unwrap();
+            }
+            assert_eq!(Ok(1), rx.pop());
+
+            // The sender kept by the parent is the last one
+            drop(tx);
+            assert_eq!(Err(EndpointError::Disconnected), rx.pop());
+        }
+
+        #[test]
+        fn senders_gone() {
+            let (tx, rx) = ipc_queue::<_, 8>().unwrap();
+            let tx2 = tx.clone();
+            tx.push(1).unwrap();
+            drop(tx);
+            tx2.push(2).unwrap();
+            drop(tx2);
+
+            assert_eq!(Ok(1), rx.pop());
+            assert_eq!(Ok(Some(2)), rx.try_pop());
+            assert_eq!(Err(EndpointError::Disconnected), rx.pop());
+            assert_eq!(Err(EndpointError::Disconnected), rx.try_pop());
+            assert_eq!(Err(EndpointError::Disconnected), rx.timed_pop(Duration::from_secs(10)));
+        }
+
+        #[test]
+        fn receivers_gone() {
+            let (tx, rx) = ipc_queue::<_, 8>().unwrap();
+            let rx2 = rx.clone();
+            drop(rx);
+            tx.push(1).unwrap();
+            drop(rx2);
+            assert_eq!(Err(EndpointError::Disconnected), tx.push(2));
+        }
+
+        #[test]
+        fn wake_on_disconnect() {
+            let (tx, rx) = ipc_queue::<i32, 8>().unwrap();
+            let receiver = thread::spawn(move || rx.pop());
+            thread::sleep(Duration::from_millis(10));
+            drop(tx);
+            assert_eq!(Err(EndpointError::Disconnected), receiver.join().unwrap());
+
+            let (tx, rx) = ipc_queue::<_, 8>().unwrap();
+            for i in 0..8 {
+                tx.push(i).unwrap();
+            }
+            let sender = thread::spawn(move || tx.push(8));
+            thread::sleep(Duration::from_millis(10));
+            drop(rx);
+            assert_eq!(Err(EndpointError::Disconnected), sender.join().unwrap());
+        }
+
+        #[test]
+        fn producers_exit_ipc() {
+            let (tx, rx) = ipc_queue::<_, 8>().unwrap();
+
+            for p in 0..4 {
+                let tx = tx.clone();
+                process::spawn(move || {
+                    for i in 0..250 {
+                        tx.push(p * 250 + i).unwrap();
+                    }
+                }).unwrap();
+            }
+            drop(tx);
+
+            let mut received = Vec::new();
+            loop {
+                match rx.pop() {
+                    Ok(value) => received.push(value),
+                    Err(EndpointError::Disconnected) => break,
+                    Err(err) => panic!("pop failed: {:?}", err)
+                }
+            }
+            received.sort();
+            assert_eq!((0..1000).collect::<Vec<_>>(), received);
+        }
+
+        #[test]
+        fn iter() {
+            let (tx, rx) = ipc_queue::<_, 8>().unwrap();
+            process::spawn(move || {
+                for i in 0..1000 {
+                    tx.push(i).unwrap();
+                }
+            }).unwrap();
+
+            let received = rx.iter().collect::<Result<Vec<_>, _>>().unwrap();
+            assert_eq!((0..1000).collect::<Vec<_>>(), received);
+            assert_eq!(None, rx.iter().next());
+        }
+
+        #[test]
+        fn try_iter() {
+            let (tx, rx) = ipc_queue::<_, 8>().unwrap();
+            for i in 0..3 {
+                tx.push(i).unwrap();
+            }
+            assert_eq!(vec![Ok(0), Ok(1), Ok(2)], rx.try_iter().collect::<Vec<_>>());
+            assert_eq!(0, rx.try_iter().count());
+
+            tx.push(3).unwrap();
+            drop(tx);
+            assert_eq!(vec![Ok(3)], rx.try_iter().collect::<Vec<_>>());
+        }
+
+        #[test]
+        fn iter_timeout() {
+            let (tx, rx) = ipc_queue::<_, 8>().unwrap();
+            tx.push(1).unwrap();
+            tx.push(2).unwrap();
+
+            let start = Instant::now();
+            assert_eq!(vec![Ok(1), Ok(2)], rx.iter_timeout(Duration::from_millis(10)).collect::<Vec<_>>());
+            assert!(start.elapsed() >= Duration::from_millis(10));
+        }
+
+        #[test]
+        fn into_iter() {
+            let (tx, rx) = ipc_queue::<_, 8>().unwrap();
+            let sender = thread::spawn(move || {
+                for i in 0..100 {
+                    tx.push(i).unwrap();
+                }
+            });
+
+            let mut sum = 0;
+            for value in &rx {
+                sum += value.unwrap();
+            }
+            assert_eq!((0..100).sum::<i32>(), sum);
+            sender.join().unwrap();
+            assert_eq!(0, rx.into_iter().count());
+        }
+
+        fn readable(fd: RawFd, timeout_ms: i32) -> bool {
+            let mut fds = [PollFd::new(fd, POLLIN, EventFlags::empty())];
+            poll(&mut fds, timeout_ms).unwrap() == 1
+        }
+
+        #[test]
+        fn eventfd() {
+            assert_eq!(None, ipc_queue::<i32, 8>().unwrap().1.as_raw_fd());
+
+            let (tx, rx) = ipc_queue_with_eventfd::<_, 8>().unwrap();
+            let fd = rx.as_raw_fd().unwrap();
+            assert!(!readable(fd, 0));
+
+            tx.push(1).unwrap();
+            tx.push(2).unwrap();
+            assert!(readable(fd, 0));
+            assert_eq!(Ok(Some(1)), rx.try_pop());
+            assert!(readable(fd, 0));
+            assert_eq!(Ok(Some(2)), rx.try_pop());
+            assert!(!readable(fd, 0));
+
+            tx.push(3).unwrap();
+            assert!(readable(fd, 0));
+            assert_eq!(Ok(3), rx.pop());
+            assert!(!readable(fd, 0));
+        }
+
+        #[test]
+        fn eventfd_disconnect() {
+            let (tx, rx) = ipc_queue_with_eventfd::<_, 8>().unwrap();
+            let fd = rx.as_raw_fd().unwrap();
+            tx.push(1).unwrap();
+            drop(tx);
+
+            // Draining the queue keeps the disconnect readable
+            assert_eq!(Ok(Some(1)), rx.try_pop());
+            assert!(readable(fd, 0));
+            assert_eq!(Err(EndpointError::Disconnected), rx.try_pop());
+        }
+
+        #[test]
+        fn space_eventfd() {
+            let (tx, rx) = ipc_queue_with_eventfd::<_, 8>().unwrap();
+            let fd = tx.as_raw_fd().unwrap();
+            assert!(readable(fd, 0));
+
+            for i in 0..8 {
+                assert_eq!(Ok(Ok(())), tx.try_push(i));
+            }
+            assert_eq!(Ok(Err(Full(8))), tx.try_push(8));
+            assert!(!readable(fd, 0));
+            assert_eq!(Ok(Some(0)), rx.try_pop());
+            assert!(readable(fd, 0));
+
+            drop(rx);
+            assert_eq!(Err(EndpointError::Disconnected), tx.try_push(8));
+            assert!(readable(fd, 0));
+        }
+
+        #[test]
+        fn eventfd_ipc() {
+            let (tx, rx) = ipc_queue_with_eventfd::<_, 8>().unwrap();
+            let fd = rx.as_raw_fd().unwrap();
+            process::spawn(move || {
+                for i in 0..100 {
+                    tx.push(i).unwrap();
+                }
+            }).unwrap();
+
+            let mut received = Vec::new();
+            while received.len() < 100 {
+                assert!(readable(fd, 5000));
+                for value in rx.try_iter() {
+                    received.push(value.unwrap());
+                }
+            }
+            assert_eq!((0..100).collect::<Vec<_>>(), received);
+        }
+    }
+}
@@ -0,0 +1,353 @@
+use nix::Error;
+use nix::Errno;
+use ::shm::Shm;
+use ::process;
+use ::pthread::PthreadPrimitiveConstructor;
+use ::pthread::PthreadWrappingPrimitiveConstructor;
+use ::pthread::Condvar;
+use ::pthread::Mutex;
+use ::queue::{Queue, Full, RING_BUFFER_SIZE};
+use ::layout::{SharedLayout, SharedOption, TypeLayout};
+
+use std::array;
+use std::fmt::Debug;
+use std::mem;
+use std::time::{Duration, Instant};
+
+/// Maximum number of simultaneous clients of an `RpcChannel`.
+pub const MAX_CLIENTS: usize = 16;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum RpcError {
+    /// No reply arrived in time. A late reply is discarded.
+    Timeout,
+    Sys(Error)
+}
+
+impl From<Error> for RpcError {
+    fn from(err: Error) -> Self {
+        RpcError::Sys(err)
+    }
+}
+
+/// Request as queued for the server, addressed to the caller's reply slot.
+#[repr(C)]
+#[derive(Copy, Clone, Debug)]
+struct Request<Req> {
+    client: u64,
+    id: u64,
+    body: Req
+}
+
+unsafe impl<Req: SharedLayout> SharedLayout for Request<Req> {
+    fn layout() -> TypeLayout {
+        TypeLayout::new::<Self>("Request")
+            .field("client", mem::offset_of!(Self, client), |s: &Self| &s.client)
+            .field("id", mem::offset_of!(Self, id), |s: &Self| &s.id)
+            .field("body", mem::offset_of!(Self, body), |s: &Self| &s.body)
+    }
+}
+
+/// Reply slot of one client.
+#[repr(C)]
+#[derive(Copy, Clone, Debug)]
+struct ReplySlot<Resp: Copy> {
+    in_use: bool,
+    /// Last correlation id handed out. Kept across clients reusing the
+    /// slot, so a late reply never matches a newer call.
+    last_id: u64,
+    /// Id of the call waiting for a reply, if any.
+    awaiting: SharedOption<u64>,
+    reply: SharedOption<Resp>
+}
+
+impl<Resp: Copy> ReplySlot<Resp> {
+    fn new() -> Self {
+        ReplySlot {
+            in_use: false,
+            last_id: 0,
+            awaiting: SharedOption::none(),
+            reply: SharedOption::none()
+        }
+    }
+}
+
+unsafe impl<Resp: Copy + SharedLayout> SharedLayout for ReplySlot<Resp> {
+    fn layout() -> TypeLayout {
+        TypeLayout::new::<Self>("ReplySlot")
+            .field("in_use", mem::offset_of!(Self, in_use), |s: &Self| &s.in_use)
+            .field("last_id", mem::offset_of!(Self, last_id), |s: &Self| &s.last_id)
+            .field("awaiting", mem::offset_of!(Self, awaiting), |s: &Self| &s.awaiting)
+            .field("reply", mem::offset_of!(Self, reply), |s: &Self| &s.reply)
+    }
+}
+
+/// Request/response channel: clients `call` with a request and block for
+/// the matching response, which a server produces with `serve`.
+///
+/// Requests go through a queue of `N`. Each client owns one of
+/// `MAX_CLIENTS` reply slots, and replies carry the request's correlation
+/// id, so a reply only ever reaches the call which is still waiting for it.
+#[repr(C)]
+pub struct RpcChannel<Req, Resp, const N: usize = RING_BUFFER_SIZE>
+    where Req: Copy, Resp: Copy
+{
+    requests: Queue<Request<Req>, N>,
+    slots: Mutex<[ReplySlot<Resp>; MAX_CLIENTS]>,
+    replied: [Condvar; MAX_CLIENTS]
+}
+
+impl<Req, Resp, const N: usize> PthreadPrimitiveConstructor for RpcChannel<Req, Resp, N>
+    where Req: Copy, Resp: Copy
+{
+    fn new() -> Self {
+        RpcChannel {
+            requests: Queue::new(),
+            slots: Mutex::new([ReplySlot::new(); MAX_CLIENTS]),
+            replied: array::from_fn(|_| Condvar::new())
+        }
+    }
+
+    fn pshared() -> Self {
+        RpcChannel {
+            requests: Queue::pshared(),
+            slots: Mutex::pshared([ReplySlot::new(); MAX_CLIENTS]),
+            replied: array::from_fn(|_| Condvar::pshared())
+        }
+    }
+}
+
+unsafe impl<Req, Resp, const N: usize> SharedLayout for RpcChannel<Req, Resp, N>
+    where Req: Copy + SharedLayout, Resp: Copy + SharedLayout
+{
+    fn layout() -> TypeLayout {
+        TypeLayout::new::<Self>("RpcChannel")
+            .field("requests", mem::offset_of!(Self, requests), |s: &Self| &s.requests)
+            .field("slots", mem::offset_of!(Self, slots), |s: &Self| &s.slots)
+            .field("replied", mem::offset_of!(Self, replied), |s: &Self| &s.replied)
+    }
+}
+
+#[allow(dead_code)]
+impl<Req, Resp, const N: usize> RpcChannel<Req, Resp, N>
+    where Req: Copy + Debug, Resp: Copy
+{
+    /// Handles requests with `handler` forever. Only returns on failure.
+    pub fn serve<F>(&self, mut handler: F) -> Result<(), Error>
+        where F: FnMut(Req) -> Resp
+    {
+        loop {
+            self.serve_one(&mut handler)?;
+        }
+    }
+
+    /// Handles one request with `handler`, blocking until there is one.
+    pub fn serve_one<F>(&self, handler: F) -> Result<(), Error>
+        where F: FnOnce(Req) -> Resp
+    {
+        let request = self.requests.pop()?;
+        self.reply(request, handler(request.body))
+    }
+
+    /// Like `serve_one`, but gives up after `time`.
+    /// Returns whether a request was handled.
+    pub fn serve_one_timeout<F>(&self, handler: F, time: Duration) -> Result<bool, Error>
+        where F: FnOnce(Req) -> Resp
+    {
+        match self.requests.timed_pop(time)? {
+            Some(request) => self.reply(request, handler(request.body)).map(|_| true),
+            None => Ok(false)
+        }
+    }
+
+    /// Number of current clients.
+    pub fn clients(&self) -> Result<usize, Error> {
+        Ok(self.slots.lock()?.iter().filter(|slot| slot.in_use).count())
+    }
+
+    fn reply(&self, request: Request<Req>, response: Resp) -> Result<(), Error> {
+        let mut slots = self.slots.lock()?;
+        let slot = &mut slots[request.client as usize];
+        // The caller may have timed out meanwhile
+        if slot.in_use && slot.awaiting.as_ref() == Some(&request.id) {
+            slot.reply = SharedOption::some(response);
+            self.replied[request.client as usize].signal()?;
+        }
+        Ok(())
+    }
+}
+
+/// Client of an `RpcChannel`, created with `RpcClient::new`.
+/// Dropping it releases its reply slot; for a client moved into
+/// `process::spawn` that is up to the child.
+pub struct RpcClient<Req, Resp, const N: usize = RING_BUFFER_SIZE>
+    where Req: Copy, Resp: Copy
+{
+    channel: Shm<RpcChannel<Req, Resp, N>>,
+    id: usize
+}
+
+#[allow(dead_code)]
+impl<Req, Resp, const N: usize> RpcClient<Req, Resp, N>
+    where Req: Copy + Debug, Resp: Copy
+{
+    /// Takes a reply slot of `channel`.
+    /// Fails with `EUSERS` if there are `MAX_CLIENTS` already.
+    pub fn new(channel: &Shm<RpcChannel<Req, Resp, N>>) -> Result<Self, Error> {
+        let mut slots = channel.slots.lock()?;
+        let id = slots.iter()
+            .position(|slot| !slot.in_use)
+            .ok_or(Error::Sys(Errno::EUSERS))?;
+        slots[id].in_use = true;
+
+        Ok(RpcClient {
+            channel: channel.clone(),
+            id
+        })
+    }
+
+    /// Sends `request` and blocks for its response, for `timeout` at most
+    /// in total.
+    pub fn call(&self, request: Req, timeout: Duration) -> Result<Resp, RpcError> {
+        let deadline = Instant::now() + timeout;
+        let channel = &*self.channel;
+
+        let id = {
+            let mut slots = channel.slots.lock()?;
+            let slot = &mut slots[self.id];
+            slot.last_id += 1;
+            slot.awaiting = SharedOption::some(slot.last_id);
+            slot.reply = SharedOption::none();
+            slot.last_id
+        };
+
+        let request = Request { client: self.id as u64, id, body: request };
+        if let Err(Full(_)) = channel.requests.push_deadline(request, deadline)? {
+            channel.slots.lock()?[self.id].awaiting = SharedOption::none();
+            return Err(RpcError::Timeout);
+        }
+
+        let mut slots = channel.slots.lock()?;
+        loop {
+            if let Some(response) = slots[self.id].reply.take() {
+                slots[self.id].awaiting = SharedOption::none();
+                return Ok(response);
+            }
+            if Instant::now() >= deadline {
+                slots[self.id].awaiting = SharedOption::none();
+                return Err(RpcError::Timeout);
+            }
+            slots = match channel.replied[self.id].wait_until(slots, deadline) {
+                Err(Error::Sys(Errno::ETIMEDOUT)) => channel.slots.lock()?,
+                Err(err) => return Err(err.into()),
+                Ok(guard) => guard
+            };
+        }
+    }
+}
+
+impl<Req, Resp, const N: usize> Drop for RpcClient<Req, Resp, N>
+    where Req: Copy, Resp: Copy
+{
+    fn drop(&mut self) {
+        if process::handing_over() {
+            return;
+        }
+        if let Ok(mut slots) = self.channel.slots.lock() {
+            let slot = &mut slots[self.id];
+            slot.in_use = false;
+            slot.awaiting = SharedOption::none();
+            slot.reply = SharedOption::none();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{RpcChannel, RpcClient, RpcError, MAX_CLIENTS};
+    use ::pthread::PthreadPrimitiveConstructor;
+    use ::process;
+    use ::shm::Shm;
+    use nix::Error;
+    use nix::Errno;
+    use std::process::exit;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn clients() {
+        let channel: Shm<RpcChannel<i32, i32>> = Shm::new(RpcChannel::pshared()).unwrap();
+        let clients = (0..MAX_CLIENTS)
+            .map(|_| RpcClient::new(&channel).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(Err(Error::Sys(Errno::EUSERS)), RpcClient::new(&channel).map(|_| ()));
+
+        drop(clients);
+        assert_eq!(Ok(0), channel.clients());
+        assert!(RpcClient::new(&channel).is_ok());
+    }
+
+    #[test]
+    fn token() {
+        let channel: Shm<RpcChannel<u32, u32>> = Shm::new(RpcChannel::pshared()).unwrap();
+        let other = Shm::<RpcChannel<u32, u32>>::from_token(&channel.token()).unwrap();
+        let client = RpcClient::new(&other).unwrap();
+
+        let server = {
+            let channel = channel.clone();
+            process::spawn(move || channel.serve_one(|x| x + 1).unwrap()).unwrap()
+        };
+        assert_eq!(Ok(2), client.call(1, Duration::from_secs(5)));
+        server.wait(None).unwrap();
+    }
+
+    #[test]
+    fn late_reply() {
+        let channel: Shm<RpcChannel<i32, i32>> = Shm::new(RpcChannel::pshared()).unwrap();
+        let client = RpcClient::new(&channel).unwrap();
+
+        let start = Instant::now();
+        assert_eq!(Err(RpcError::Timeout), client.call(1, Duration::from_millis(20)));
+        assert!(start.elapsed() >= Duration::from_millis(20));
+
+        // The server only gets to the first call after it timed out, so its
+        // reply must not be taken for the second one.
+        let server = {
+            let channel = channel.clone();
+            process::spawn(move || {
+                for _ in 0..2 {
+                    channel.serve_one(|x| x * 10).unwrap();
+                }
+            }).unwrap()
+        };
+        assert_eq!(Ok(20), client.call(2, Duration::from_secs(5)));
+        server.wait(None).unwrap();
+    }
+
+    #[test]
+    fn concurrent_clients_ipc() {
+        let channel: Shm<RpcChannel<(i32, i32), i32>> = Shm::new(RpcChannel::pshared()).unwrap();
+
+        let children = (0..4).map(|_| {
+            let client = RpcClient::new(&channel).unwrap();
+            process::spawn(move || {
+                for i in 0..100 {
+                    if client.call((client.id as i32, i), Duration::from_secs(5)) != Ok(client.id as i32 * 1000 + i) {
+                        exit(1);
+                    }
+                }
+            }).unwrap()
+        }).collect::<Vec<_>>();
+
+        for _ in 0..400 {
+            assert_eq!(Ok(true), channel.serve_one_timeout(|(a, b)| a * 1000 + b, Duration::from_secs(5)));
+        }
+
+        for child in children {
+            match child.wait(None).unwrap() {
+                process::WaitStatus::Exited(_, 0) => (),
+                other => panic!("client failed: {:?}", other)
+            }
+        }
+        assert_eq!(Ok(0), channel.clients());
+    }
+}
@@ -0,0 +1,230 @@
+use nix::Error;
+use nix::Errno;
+use nix::poll::{poll, PollFd, EventFlags, POLLIN, POLLNVAL};
+use ::queue::{Sender, Receiver};
+
+use std::marker::PhantomData;
+use std::os::unix::io::RawFd;
+use std::time::{Duration, Instant};
+
+/// Queue endpoints a `Select` can wait on, through the eventfds set up by
+/// `ipc_queue_with_eventfd`.
+pub trait Selectable {
+    /// Eventfd which is readable while a value can be popped.
+    fn recv_eventfd(&self) -> Option<RawFd>;
+    /// Eventfd which is readable while a value can be pushed.
+    fn send_eventfd(&self) -> Option<RawFd>;
+}
+
+impl<T, const N: usize> Selectable for Receiver<T, N>
+    where T: Copy
+{
+    fn recv_eventfd(&self) -> Option<RawFd> {
+        self.as_raw_fd()
+    }
+
+    fn send_eventfd(&self) -> Option<RawFd> {
+        None
+    }
+}
+
+impl<T, const N: usize> Selectable for Sender<T, N>
+    where T: Copy
+{
+    fn recv_eventfd(&self) -> Option<RawFd> {
+        None
+    }
+
+    fn send_eventfd(&self) -> Option<RawFd> {
+        self.as_raw_fd()
+    }
+}
+
+/// Waits until any of several queues, of any value type, has a value to pop
+/// or space to push, sleeping in `poll` on their eventfds.
+///
+/// Operations are numbered in the order they were added. Readiness is only
+/// a hint when other processes use the queues too, so callers should follow
+/// up with a `try_` operation and select again if it fails.
+#[allow(dead_code)]
+pub struct Select<'a> {
+    fds: Vec<PollFd>,
+    /// Where the next scan for ready operations starts, so a busy queue
+    /// can't starve the others.
+    next: usize,
+    queues: PhantomData<&'a ()>
+}
+
+#[allow(dead_code)]
+impl<'a> Select<'a> {
+    pub fn new() -> Self {
+        Select { fds: Vec::new(), next: 0, queues: PhantomData }
+    }
+
+    /// Adds waiting for a value in `queue`, returning the operation's index.
+    /// Fails with `EINVAL` if the queue has no eventfd.
+    pub fn recv<S: Selectable>(&mut self, queue: &'a S) -> Result<usize, Error> {
+        self.add(queue.recv_eventfd())
+    }
+
+    /// Adds waiting for space in `queue`, returning the operation's index.
+    /// Fails with `EINVAL` if the queue has no eventfd.
+    pub fn send<S: Selectable>(&mut self, queue: &'a S) -> Result<usize, Error> {
+        self.add(queue.send_eventfd())
+    }
+
+    /// Blocks until an operation is ready and returns its index.
+    pub fn ready(&mut self) -> Result<usize, Error> {
+        match self.ready_until(None)? {
+            Some(index) => Ok(index),
+            None => unreachable!("select without deadline timed out")
+        }
+    }
+
+    /// Like `ready`, but gives up after `time`.
+    pub fn ready_timeout(&mut self, time: Duration) -> Result<Option<usize>, Error> {
+        self.ready_until(Some(Instant::now() + time))
+    }
+
+    /// Like `ready`, but gives up at `deadline`.
+    pub fn ready_deadline(&mut self, deadline: Instant) -> Result<Option<usize>, Error> {
+        self.ready_until(Some(deadline))
+    }
+
+    fn add(&mut self, eventfd: Option<RawFd>) -> Result<usize, Error> {
+        let eventfd = eventfd.ok_or(Error::Sys(Errno::EINVAL))?;
+        self.fds.push(PollFd::new(eventfd, POLLIN, EventFlags::empty()));
+        Ok(self.fds.len() - 1)
+    }
+
+    fn ready_until(&mut self, deadline: Option<Instant>) -> Result<Option<usize>, Error> {
+        loop {
+            let timeout = match deadline {
+                None => -1,
+                Some(deadline) => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        0
+                    } else {
+                        // Round up, so we don't wake just before the deadline
+                        let remaining = deadline - now;
+                        let ms = remaining.as_nanos().div_ceil(1_000_000);
+                        ms.min(i32::MAX as u128) as i32
+                    }
+                }
+            };
+
+            match poll(&mut self.fds, timeout) {
+                Ok(0) => {
+                    if timeout == 0 {
+                        return Ok(None);
+                    }
+                }
+                Ok(_) => return self.take_ready().map(Some),
+                Err(Error::Sys(Errno::EINTR)) => {}
+                Err(err) => return Err(err)
+            }
+        }
+    }
+
+    /// Returns the first ready operation, starting after the last one returned.
+    fn take_ready(&mut self) -> Result<usize, Error> {
+        let len = self.fds.len();
+        for offset in 0..len {
+            let index = (self.next + offset) % len;
+            let revents = self.fds[index].revents().unwrap_or_else(EventFlags::empty);
+            if revents.contains(POLLNVAL) {
+                return Err(Error::Sys(Errno::EBADF));
+            }
+            if !revents.is_empty() {
+                self.next = index + 1;
+                return Ok(index);
+            }
+        }
+        unreachable!("poll reported a ready descriptor")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Select;
+    use ::queue::{ipc_queue, ipc_queue_with_eventfd, Full};
+    use ::process;
+    use nix::Error;
+    use nix::Errno;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn timeout() {
+        let (_tx, rx) = ipc_queue_with_eventfd::<i32, 8>().unwrap();
+        let mut select = Select::new();
+        select.recv(&rx).unwrap();
+
+        let start = Instant::now();
+        assert_eq!(Ok(None), select.ready_timeout(Duration::from_millis(20)));
+        assert!(start.elapsed() >= Duration::from_millis(20));
+    }
+
+    #[test]
+    fn without_eventfd() {
+        let (tx, rx) = ipc_queue::<i32, 8>().unwrap();
+        let mut select = Select::new();
+        assert_eq!(Err(Error::Sys(Errno::EINVAL)), select.recv(&rx));
+        assert_eq!(Err(Error::Sys(Errno::EINVAL)), select.send(&tx));
+    }
+
+    #[test]
+    fn recv_and_send() {
+        let (number_tx, numbers) = ipc_queue_with_eventfd::<i32, 2>().unwrap();
+        let (name_tx, names) = ipc_queue_with_eventfd::<char, 2>().unwrap();
+        number_tx.push(1).unwrap();
+        number_tx.push(2).unwrap();
+
+        let mut select = Select::new();
+        let number_in = select.recv(&numbers).unwrap();
+        let name_in = select.recv(&names).unwrap();
+        let number_out = select.send(&number_tx).unwrap();
+        assert_eq!(Ok(Some(number_in)), select.ready_timeout(Duration::from_millis(0)));
+
+        assert_eq!(Ok(Some(1)), numbers.try_pop());
+        assert_eq!(Ok(Some(2)), numbers.try_pop());
+        assert_eq!(Ok(Some(number_out)), select.ready_timeout(Duration::from_millis(0)));
+
+        assert_eq!(Ok(Ok(())), number_tx.try_push(3));
+        assert_eq!(Ok(Ok(())), number_tx.try_push(4));
+        assert_eq!(Ok(Err(Full(5))), number_tx.try_push(5));
+        name_tx.push('a').unwrap();
+        let ready = (0..2).map(|_| select.ready().unwrap()).collect::<Vec<_>>();
+        assert!(ready.contains(&number_in) && ready.contains(&name_in));
+    }
+
+    #[test]
+    fn control_and_data_ipc() {
+        let (control_tx, control) = ipc_queue_with_eventfd::<bool, 8>().unwrap();
+        let (tx, rx) = ipc_queue_with_eventfd::<_, 8>().unwrap();
+
+        process::spawn(move || {
+            for i in 0..100u64 {
+                tx.push(i).unwrap();
+            }
+            control_tx.push(true).unwrap();
+        }).unwrap();
+
+        let mut select = Select::new();
+        let stop = select.recv(&control).unwrap();
+        let data = select.recv(&rx).unwrap();
+
+        let mut received = Vec::new();
+        loop {
+            let index = select.ready().unwrap();
+            if index == stop && control.try_pop().unwrap() == Some(true) {
+                break;
+            }
+            if index == data {
+                received.extend(rx.try_iter().map(Result::unwrap));
+            }
+        }
+        received.extend(rx.try_iter().map(Result::unwrap));
+        assert_eq!((0..100).collect::<Vec<_>>(), received);
+    }
+}
@@ -2,40 +2,106 @@ use rand::Rng;
 use rand::thread_rng;
 
 use nix::Result;
+use nix::Error;
+use nix::Errno;
 use nix::sys::mman;
 use nix::c_void;
+use nix::libc;
 use nix::fcntl;
 use nix::unistd::{ftruncate, close};
 use nix::sys::stat;
+use std::fmt;
+use std::fs::File;
+use std::io;
+use std::io::{Read, Write};
 use std::mem;
+use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd};
+use std::os::unix::process::CommandExt;
+use std::path::Path;
+use std::process::Command;
 use std::ptr;
-use std::sync::atomic::{AtomicUsize, Ordering};
+use std::slice;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use ::layout::{LayoutMismatch, SharedLayout, TypeLayout};
+use ::process;
 
 type RawFd = i32;
 
+const SHM_MAGIC: u64 = 0x5348_4d5f_4950_4331;
+
+/// Room for the shm name in the segment header, including padding.
+const SHM_NAME_LEN: usize = 32;
+
+/// Types whose raw bytes can be saved to and loaded from a checkpoint file.
+///
+/// Implementors must not hold pointers or other process-local state, since
+/// a restored value is a plain byte copy of the saved one.
+///
+/// # Safety
+///
+/// The bytes of the value must stay meaningful in another process and at
+/// another address.
+pub unsafe trait Checkpoint {
+    type Guard<'a> where Self: 'a;
+
+    /// Takes every lock of `self`, so its bytes can be copied consistently.
+    fn freeze(&self) -> Result<Self::Guard<'_>>;
+
+    /// Reinitializes synchronization primitives of a freshly restored value,
+    /// which were copied in the state `freeze` left them in.
+    fn thaw(&mut self);
+}
+
+/// Plain data without pointers, references or other process-local state,
+/// which is checkpointed as its raw bytes.
+///
+/// # Safety
+///
+/// Implementors must only hold plain data, whose bytes mean the same in
+/// another process and at another address.
+pub unsafe trait PlainData: Copy {}
+
+macro_rules! plain_data {
+    ($($ty:ty),*) => {
+        $(unsafe impl PlainData for $ty {})*
+    }
+}
+
+plain_data!(u8, u16, u32, u64, u128, usize, i8, i16, i32, i64, i128, isize, f32, f64, bool, char, ());
+
+unsafe impl<T: PlainData, const N: usize> PlainData for [T; N] {}
+
+unsafe impl<T: PlainData> Checkpoint for T {
+    type Guard<'a> = () where T: 'a;
+
+    fn freeze(&self) -> Result<()> {
+        Ok(())
+    }
+
+    fn thaw(&mut self) {}
+}
+
+/// Handle to a value in a shared memory segment, reference counted across
+/// processes. A handle moved into `process::spawn` is only released by the
+/// child.
 #[derive(Debug)]
 pub struct Shm<T> {
     inner_ptr: *mut ShmInner<T>,
+    name: String,
 }
 
 unsafe impl<T> Send for Shm<T> {}
 
 impl<T> Shm<T> {
     pub fn new(obj: T) -> Result<Self> {
-        let shm_path = thread_rng()
-            .gen_ascii_chars()
-            .take(10)
-            .collect::<String>();
-
-        let shm_fd = Self::open_shm(&shm_path)?;
-        let raw_ptr = Self::mmap_shm(shm_fd)?;
-
-        close(shm_fd)?;
+        let (raw_ptr, shm_path) = Self::create_shm()?;
 
         unsafe {
-            ptr::write(raw_ptr, ShmInner::new(obj));
+            ptr::write(raw_ptr, ShmInner::new(obj, &shm_path));
             Ok(Shm {
                 inner_ptr: raw_ptr,
+                name: shm_path,
             })
         }   
     }
@@ -45,10 +111,23 @@ impl<T> Shm<T> {
         (*self.inner_ptr).get_raw_data()
     }
 
+    fn create_shm() -> Result<(*mut ShmInner<T>, String)> {
+        let shm_path = thread_rng()
+            .gen_ascii_chars()
+            .take(10)
+            .collect::<String>();
+
+        let shm_fd = Self::open_shm(&shm_path)?;
+        let raw_ptr = Self::mmap_shm(shm_fd)?;
+
+        close(shm_fd)?;
+        Ok((raw_ptr, shm_path))
+    }
+
     fn open_shm(shm_name: &str) -> Result<RawFd> {
         let fd = mman::shm_open(shm_name, 
                                 fcntl::O_RDWR | fcntl::O_CREAT | fcntl::O_EXCL,
-                                stat::Mode::empty())?;                             
+                                stat::S_IRUSR | stat::S_IWUSR)?;                             
         ftruncate(fd, mem::size_of::<ShmInner<T>>() as i64)?;
         Ok(fd)
     }
@@ -63,7 +142,309 @@ impl<T> Shm<T> {
         Ok(void_ptr as *mut ShmInner<T>)
     }
 
+    #[allow(dead_code)]
+    fn attach_shm(fd: RawFd) -> Result<*mut ShmInner<T>> {
+        if stat::fstat(fd)?.st_size as usize != mem::size_of::<ShmInner<T>>() {
+            return Err(Error::Sys(Errno::EINVAL));
+        }
+        Self::mmap_shm(fd)
+    }
+}
+
+#[allow(dead_code)]
+#[derive(Clone, Debug, Eq, PartialEq)]
+enum ShmSource {
+    Name(String),
+    Fd(RawFd),
+}
+
+/// Serializable reference to a `Shm` segment which can be handed to another
+/// program through an environment variable or argv.
+///
+/// Format: `name:<shm name>:<size>:<layout>` or `fd:<fd>:<size>:<layout>`,
+/// where `layout` is the `TypeLayout` of the shared type in the creating
+/// program, in its textual form.
+#[allow(dead_code)]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ShmToken {
+    source: ShmSource,
+    size: usize,
+    layout: TypeLayout,
+}
+
+impl fmt::Display for ShmToken {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.source {
+            ShmSource::Name(ref name) => write!(f, "name:{}", name)?,
+            ShmSource::Fd(fd) => write!(f, "fd:{}", fd)?,
+        }
+        write!(f, ":{}:{}", self.size, self.layout)
+    }
+}
+
+impl FromStr for ShmToken {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let invalid = || Error::Sys(Errno::EINVAL);
+
+        // The layout comes last, as it may contain colons itself
+        let mut fields = s.splitn(4, ':');
+        let kind = fields.next().ok_or_else(invalid)?;
+        let source = fields.next().ok_or_else(invalid)?;
+        let size = fields.next().ok_or_else(invalid)?;
+        let layout = fields.next().ok_or_else(invalid)?;
+
+        let source = match kind {
+            "name" => ShmSource::Name(source.to_owned()),
+            "fd" => ShmSource::Fd(source.parse().map_err(|_| invalid())?),
+            _ => return Err(invalid()),
+        };
+
+        Ok(ShmToken {
+            source,
+            size: size.parse().map_err(|_| invalid())?,
+            layout: layout.parse().map_err(|_| invalid())?,
+        })
+    }
+}
+
+#[allow(dead_code)]
+impl<T> Shm<T>
+    where T: Checkpoint + SharedLayout
+{
+    /// Dumps a header describing `T` and the raw bytes of the shared value
+    /// to `path`, holding the value's locks while copying.
+    pub fn checkpoint<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let header = CheckpointHeader::new::<T>();
+        let mut file = File::create(path).map_err(io_error)?;
+
+        self.freeze().and_then(|_guard| unsafe {
+            file.write_all(as_bytes(&header)).map_err(io_error)?;
+            file.write_all(as_bytes(&(*self.inner_ptr).data)).map_err(io_error)
+        })
+    }
+
+    /// Loads a checkpoint written by `checkpoint` into a new segment.
+    /// Fails with `EINVAL` if it was saved for a different type.
+    pub fn restore<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let header_size = mem::size_of::<CheckpointHeader>();
+        let mut bytes = Vec::new();
+        File::open(path)
+            .and_then(|mut file| file.read_to_end(&mut bytes))
+            .map_err(io_error)?;
+
+        if bytes.len() != header_size + mem::size_of::<T>() {
+            return Err(Error::Sys(Errno::EINVAL));
+        }
+        let header = unsafe {
+            ptr::read_unaligned(bytes.as_ptr() as *const CheckpointHeader)
+        };
+        if header != CheckpointHeader::new::<T>() {
+            return Err(Error::Sys(Errno::EINVAL));
+        }
+
+        let (raw_ptr, shm_path) = Self::create_shm()?;
+        unsafe {
+            ptr::addr_of_mut!((*raw_ptr).header).write(ShmHeader::new::<T>(&shm_path));
+            ptr::addr_of_mut!((*raw_ptr).ref_ctr).write(AtomicU64::new(1));
+            ptr::copy_nonoverlapping(bytes[header_size..].as_ptr(),
+                                     ptr::addr_of_mut!((*raw_ptr).data) as *mut u8,
+                                     mem::size_of::<T>());
+            (*raw_ptr).data.thaw();
+        }
+
+        Ok(Shm {
+            inner_ptr: raw_ptr,
+            name: shm_path,
+        })
+    }
+}
+
+#[allow(dead_code)]
+impl<T> Shm<T>
+    where T: Checkpoint
+{
+    /// Maps the segment's backing object once more with `MAP_PRIVATE` and
+    /// returns a frozen copy-on-write view of the shared value.
+    ///
+    /// The value's locks are only held while every page of the private
+    /// mapping is faulted in as a private copy, after that writers continue
+    /// to update the shared mapping without affecting the snapshot.
+    /// Synchronization primitives inside the snapshot are reinitialized.
+    pub fn snapshot(&self) -> Result<Snapshot<T>> {
+        let size = mem::size_of::<ShmInner<T>>();
+        let fd = mman::shm_open(self.name.as_str(), fcntl::O_RDONLY, stat::Mode::empty())?;
+        let void_ptr = mman::mmap(ptr::null_mut(),
+                                  size,
+                                  mman::PROT_READ | mman::PROT_WRITE,
+                                  mman::MAP_PRIVATE,
+                                  fd,
+                                  0);
+        close(fd)?;
+        let raw_ptr = void_ptr? as *mut ShmInner<T>;
+
+        let copied = self.freeze().map(|_guard| unsafe {
+            copy_private_pages(raw_ptr as *mut u8, size)
+        });
+        if let Err(err) = copied {
+            mman::munmap(raw_ptr as *mut c_void, size)?;
+            return Err(err);
+        }
+
+        unsafe {
+            (*raw_ptr).data.thaw();
+        }
+
+        Ok(Snapshot {
+            inner_ptr: raw_ptr,
+        })
+    }
+}
+
+/// Private copy-on-write view of a `Shm` segment created by `Shm::snapshot`.
+pub struct Snapshot<T> {
+    inner_ptr: *mut ShmInner<T>,
+}
+
+impl<T> Deref for Snapshot<T> {
+    type Target = T;
+    fn deref(&self) -> &Self::Target {
+        unsafe {
+            &(*self.inner_ptr).data
+        }
+    }
+}
 
+impl<T> Drop for Snapshot<T> {
+    fn drop(&mut self) {
+        mman::munmap(self.inner_ptr as *mut c_void, mem::size_of::<ShmInner<T>>())
+            .unwrap();
+    }
+}
+
+/// Writes every page of a `MAP_PRIVATE` mapping back to itself, so the kernel
+/// replaces it with a private copy which no longer follows the shared object.
+unsafe fn copy_private_pages(base: *mut u8, size: usize) {
+    let page_size = libc::sysconf(libc::_SC_PAGESIZE) as usize;
+    for offset in (0..size).step_by(page_size) {
+        let byte = base.add(offset);
+        ptr::write_volatile(byte, ptr::read_volatile(byte));
+    }
+}
+
+#[allow(dead_code)]
+impl<T> Shm<T>
+    where T: SharedLayout
+{
+    /// Attaches to a segment described by `token`, e.g. one created by the
+    /// process that exec'd us. Fails with the first difference if the layout
+    /// of `T` differs from the creator's, and with `EINVAL` if the segment
+    /// doesn't match the token.
+    ///
+    /// A descriptor passed in the token is closed in any case.
+    pub fn from_token(token: &ShmToken) -> ::std::result::Result<Self, AttachError> {
+        let shm_fd = match token.source {
+            ShmSource::Name(ref name) => mman::shm_open(name.as_str(), fcntl::O_RDWR, stat::Mode::empty())?,
+            ShmSource::Fd(fd) => fd,
+        };
+
+        let shm = Self::attach_token(token, shm_fd);
+        let closed = close(shm_fd);
+        let shm = shm?;
+        closed?;
+        Ok(shm)
+    }
+
+    fn attach_token(token: &ShmToken, fd: RawFd) -> ::std::result::Result<Self, AttachError> {
+        T::layout().check(&token.layout).map_err(AttachError::Layout)?;
+        if token.size != mem::size_of::<ShmInner<T>>() {
+            return Err(AttachError::Sys(Error::Sys(Errno::EINVAL)));
+        }
+
+        let raw_ptr = Self::attach_shm(fd)?;
+        unsafe {
+            if !(*raw_ptr).header.matches::<T>() {
+                mman::munmap(raw_ptr as *mut c_void, mem::size_of::<ShmInner<T>>())?;
+                return Err(AttachError::Sys(Error::Sys(Errno::EINVAL)));
+            }
+            (*raw_ptr).increment_ref_ctr();
+
+            // The name is kept in the header, so segments attached through a
+            // descriptor can still hand out tokens and be snapshotted.
+            Ok(Shm {
+                inner_ptr: raw_ptr,
+                name: (*raw_ptr).header.name(),
+            })
+        }
+    }
+
+    /// Token referring to the segment by its shm name.
+    pub fn token(&self) -> ShmToken {
+        ShmToken {
+            source: ShmSource::Name(self.name.clone()),
+            size: mem::size_of::<ShmInner<T>>(),
+            layout: T::layout(),
+        }
+    }
+
+    /// Token referring to a fresh descriptor of the segment, which is
+    /// inherited by the programs `command` spawns, so it survives `exec`.
+    ///
+    /// The descriptor stays close-on-exec in this process and is closed
+    /// when `command` is dropped. In the new program, `from_token` closes it.
+    pub fn inheritable_token(&self, command: &mut Command) -> Result<ShmToken> {
+        let fd = mman::shm_open(self.name.as_str(), fcntl::O_RDWR, stat::Mode::empty())?;
+        let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+        let raw_fd = fd.as_raw_fd();
+
+        unsafe {
+            command.pre_exec(move || {
+                // Runs in the forked child, right before `exec`
+                if libc::fcntl(fd.as_raw_fd(), libc::F_SETFD, 0) == -1 {
+                    return Err(io::Error::last_os_error());
+                }
+                Ok(())
+            });
+        }
+
+        Ok(ShmToken {
+            source: ShmSource::Fd(raw_fd),
+            size: mem::size_of::<ShmInner<T>>(),
+            layout: T::layout(),
+        })
+    }
+}
+
+/// Error attaching to a segment with `Shm::from_token`.
+#[derive(Clone, Debug, PartialEq)]
+pub enum AttachError {
+    /// The layout of the shared type differs from the creator's.
+    Layout(LayoutMismatch),
+    Sys(Error),
+}
+
+impl From<Error> for AttachError {
+    fn from(err: Error) -> Self {
+        AttachError::Sys(err)
+    }
+}
+
+impl fmt::Display for AttachError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            AttachError::Layout(ref mismatch) => mismatch.fmt(f),
+            AttachError::Sys(ref err) => err.fmt(f),
+        }
+    }
+}
+
+unsafe fn as_bytes<T>(value: &T) -> &[u8] {
+    slice::from_raw_parts(value as *const T as *const u8, mem::size_of::<T>())
+}
+
+fn io_error(err: io::Error) -> Error {
+    Error::Sys(Errno::from_i32(err.raw_os_error().unwrap_or(Errno::EIO as i32)))
 }
 
 use std::ops::{Deref, DerefMut};
@@ -92,13 +473,18 @@ impl<T> Clone for Shm<T> {
         };
 
         Shm {
-            inner_ptr: self.inner_ptr
+            inner_ptr: self.inner_ptr,
+            name: self.name.clone(),
         }
     }
 }
 
 impl<T> Drop for Shm<T> {
     fn drop(&mut self) {
+        if process::handing_over() {
+            return;
+        }
+
         let mut inner = unsafe {
             &mut *self.inner_ptr
         };
@@ -118,15 +504,69 @@ impl<T> Drop for Shm<T> {
     }
 }
 
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+struct ShmHeader {
+    magic: u64,
+    size: u64,
+    /// Shm name of the segment, padded with zeroes.
+    name: [u8; SHM_NAME_LEN],
+}
+
+impl ShmHeader {
+    fn new<T>(name: &str) -> Self {
+        let mut header = ShmHeader {
+            magic: SHM_MAGIC,
+            size: mem::size_of::<ShmInner<T>>() as u64,
+            name: [0; SHM_NAME_LEN],
+        };
+        header.name[..name.len()].copy_from_slice(name.as_bytes());
+        header
+    }
+
+    fn matches<T>(&self) -> bool {
+        self.magic == SHM_MAGIC && self.size == mem::size_of::<ShmInner<T>>() as u64
+    }
+
+    fn name(&self) -> String {
+        let len = self.name.iter().position(|&b| b == 0).unwrap_or(SHM_NAME_LEN);
+        String::from_utf8_lossy(&self.name[..len]).into_owned()
+    }
+}
+
+/// Header of a checkpoint file, identifying the type of the saved value.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+struct CheckpointHeader {
+    magic: u64,
+    size: u64,
+    layout: u64,
+}
+
+impl CheckpointHeader {
+    fn new<T: SharedLayout>() -> Self {
+        CheckpointHeader {
+            magic: SHM_MAGIC,
+            size: mem::size_of::<T>() as u64,
+            layout: T::layout().digest(),
+        }
+    }
+}
+
+/// Contents of a segment. Everything before `data` has the same layout in
+/// every build, so programs of any pointer width can attach.
+#[repr(C)]
 struct ShmInner<T> {
-    ref_ctr: AtomicUsize,
+    header: ShmHeader,
+    ref_ctr: AtomicU64,
     data: T
 }
 
 impl<T> ShmInner<T> {
-    pub fn new(data: T) -> Self {
+    pub fn new(data: T, name: &str) -> Self {
         ShmInner {
-            ref_ctr: AtomicUsize::new(1),
+            header: ShmHeader::new::<T>(name),
+            ref_ctr: AtomicU64::new(1),
             data: data
         }
     }
@@ -139,7 +579,7 @@ impl<T> ShmInner<T> {
         self.ref_ctr.fetch_sub(1, Ordering::SeqCst);
     }
 
-    pub fn ref_count(&self) -> usize {
+    pub fn ref_count(&self) -> u64 {
         self.ref_ctr.load(Ordering::SeqCst)
     }
 
@@ -165,6 +605,13 @@ impl<T> DerefMut for ShmInner<T> {
 mod tests {
     use super::*;
     use ::process;
+    use ::pthread::Mutex;
+    use ::pthread::PthreadWrappingPrimitiveConstructor;
+    use std::env;
+    use std::fs;
+    use std::process::{Command, Stdio};
+
+    const TOKEN_VAR: &str = "SHM_TEST_TOKEN";
 
     #[test]
     fn simple() {
@@ -190,4 +637,173 @@ mod tests {
             assert_eq!(i, (*buffer)[i]);
         }
     }
+
+    #[test]
+    fn hand_over() {
+        let shm = Shm::new(1u64).unwrap();
+        let moved = shm.clone();
+        let kept = shm.clone();
+        process::spawn(move || drop(moved)).unwrap().wait(None).unwrap();
+
+        // The child released the moved handle, and the parent didn't again
+        let ref_count = || unsafe { (*shm.inner_ptr).ref_count() };
+        assert_eq!(2, ref_count());
+
+        // A handle which never reached the child is released as usual
+        drop(kept);
+        assert_eq!(1, ref_count());
+    }
+
+    #[test]
+    fn token_roundtrip() {
+        let one = Shm::new(1u64).unwrap();
+        let token = one.token();
+        assert_eq!(token, token.to_string().parse().unwrap());
+
+        let fd_token = one.inheritable_token(&mut Command::new("true")).unwrap();
+        assert_eq!(fd_token, fd_token.to_string().parse().unwrap());
+
+        // Nested layouts contain colons
+        let pair = Shm::new([1u32, 2]).unwrap().token();
+        assert_eq!(pair, pair.to_string().parse().unwrap());
+
+        assert!("name:abc:8".parse::<ShmToken>().is_err());
+        assert!("name:abc:8:u64".parse::<ShmToken>().is_err());
+        assert!("pipe:abc:8:u64(8,8)".parse::<ShmToken>().is_err());
+    }
+
+    #[test]
+    fn from_token() {
+        let mut one = Shm::new(1u64).unwrap();
+        let token = one.token().to_string();
+
+        let other = Shm::<u64>::from_token(&token.parse().unwrap()).unwrap();
+        *one = 2;
+        assert_eq!(2, *other);
+    }
+
+    fn fd_token(shm: &Shm<u64>) -> (ShmToken, RawFd) {
+        let fd = mman::shm_open(shm.name.as_str(), fcntl::O_RDWR, stat::Mode::empty()).unwrap();
+        (ShmToken { source: ShmSource::Fd(fd), ..shm.token() }, fd)
+    }
+
+    fn is_open(fd: RawFd) -> bool {
+        fcntl::fcntl(fd, fcntl::FcntlArg::F_GETFD).is_ok()
+    }
+
+    #[test]
+    fn from_fd_token() {
+        let mut one = Shm::new(1u64).unwrap();
+        let (token, fd) = fd_token(&one);
+
+        let other = Shm::<u64>::from_token(&token).unwrap();
+        assert!(!is_open(fd));
+        assert_eq!(one.token(), other.token());
+
+        let snapshot = other.snapshot().unwrap();
+        *one = 2;
+        assert_eq!((1, 2), (*snapshot, *other));
+    }
+
+    #[test]
+    fn exec() {
+        let value = Shm::new(1u64).unwrap();
+
+        let mut command = Command::new(env::current_exe().unwrap());
+        command.args(["--exact", "shm::tests::exec_child", "--test-threads=1"]);
+        command.stdout(Stdio::null());
+        let token = value.inheritable_token(&mut command).unwrap();
+        command.env(TOKEN_VAR, token.to_string());
+
+        assert!(command.status().unwrap().success());
+        assert_eq!(2, *value);
+    }
+
+    /// Other half of `exec`, which does nothing unless run from there.
+    #[test]
+    fn exec_child() {
+        let token = match env::var(TOKEN_VAR) {
+            Ok(token) => token.parse().unwrap(),
+            Err(_) => return
+        };
+
+        let mut value = Shm::<u64>::from_token(&token).unwrap();
+        assert!(Shm::<u64>::from_token(&value.token()).is_ok());
+        *value = 2;
+    }
+
+    #[test]
+    fn checkpoint_restore() {
+        let path = env::temp_dir().join(format!("shm_checkpoint_{}", process::pid()));
+
+        let mutex = Shm::new(Mutex::pshared([1, 2, 3])).unwrap();
+        mutex.checkpoint(&path).unwrap();
+        *mutex.lock().unwrap() = [4, 5, 6];
+
+        let restored = Shm::<Mutex<[i32; 3]>>::restore(&path).unwrap();
+        assert_eq!([1, 2, 3], *restored.lock().unwrap());
+        assert_eq!([4, 5, 6], *mutex.lock().unwrap());
+
+        assert!(Shm::<Mutex<[u32; 3]>>::restore(&path).is_err());
+        assert!(Shm::<Mutex<[i32; 4]>>::restore(&path).is_err());
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn snapshot() {
+        let mutex = Shm::new(Mutex::pshared([1u64; 4096])).unwrap();
+        let snapshot = mutex.snapshot().unwrap();
+
+        *mutex.lock().unwrap() = [2; 4096];
+
+        assert!(snapshot.lock().unwrap().iter().all(|&v| v == 1));
+        assert!(mutex.lock().unwrap().iter().all(|&v| v == 2));
+    }
+
+    #[test]
+    fn snapshot_ipc() {
+        let mutex = Shm::new(Mutex::pshared([0u64; 4096])).unwrap();
+        let snapshot = mutex.snapshot().unwrap();
+
+        {
+            let mutex = mutex.clone();
+            process::spawn(move || {
+                *mutex.lock().unwrap() = [1; 4096];
+            }).unwrap().wait(None).unwrap();
+        }
+
+        assert!(snapshot.lock().unwrap().iter().all(|&v| v == 0));
+        assert!(mutex.lock().unwrap().iter().all(|&v| v == 1));
+    }
+
+    #[test]
+    fn from_token_type_mismatch() {
+        let one = Shm::new(1u64).unwrap();
+        let token = one.token();
+        match Shm::<i64>::from_token(&token) {
+            Err(AttachError::Layout(mismatch)) => assert_eq!(("u64", "type", "u64", "i64"),
+                                                            (&mismatch.path[..], mismatch.what, &mismatch.expected[..], &mismatch.found[..])),
+            other => panic!("attached with a different type: {:?}", other.map(|_| ()))
+        }
+        assert!(Shm::<u32>::from_token(&token).is_err());
+    }
+
+    #[test]
+    fn from_token_layout_mismatch() {
+        let one = Shm::new(1u64).unwrap();
+        // As created by a build with a 4-byte `u64`
+        let mut token = one.token();
+        token.layout.size = 4;
+        match Shm::<u64>::from_token(&token) {
+            Err(AttachError::Layout(mismatch)) => assert_eq!(
+                "layout mismatch at `u64`: size differs (expected 4, found 8)", mismatch.to_string()),
+            other => panic!("attached with a different layout: {:?}", other.map(|_| ()))
+        }
+
+        // A descriptor passed along is closed even if attaching fails
+        let (token, fd) = fd_token(&one);
+        assert!(Shm::<u32>::from_token(&token).is_err());
+        assert!(!is_open(fd));
+    }
 }
@@ -0,0 +1,321 @@
+use nix::Result;
+use nix::Error;
+use nix::Errno;
+use ::futex::Event;
+use ::pthread::PthreadPrimitiveConstructor;
+use ::queue::{Full, RING_BUFFER_SIZE};
+use ::queue::BlockingQueue;
+use ::layout::{SharedLayout, TypeLayout};
+
+use std::cell::UnsafeCell;
+use std::mem;
+use std::mem::MaybeUninit;
+use std::result;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+/// Keeps `T` on its own cache line.
+#[repr(C, align(64))]
+pub struct CachePadded<T>(pub T);
+
+unsafe impl<T: SharedLayout> SharedLayout for CachePadded<T> {
+    fn layout() -> TypeLayout {
+        TypeLayout::new::<Self>("CachePadded")
+            .field("0", 0, |s: &Self| &s.0)
+    }
+}
+
+/// Lock-free queue for exactly one producer and one consumer process, with
+/// up to `N` values, which must be a power of two.
+///
+/// The producer and the consumer only touch their own index, kept on
+/// separate cache lines, and only enter the kernel to sleep on a full or an
+/// empty queue. Using it from several producers or consumers at once is a
+/// data race.
+#[repr(C)]
+pub struct SpscQueue<T, const N: usize = RING_BUFFER_SIZE>
+    where T: Copy
+{
+    head: CachePadded<AtomicUsize>,
+    tail: CachePadded<AtomicUsize>,
+    readable: CachePadded<Event>,
+    writable: CachePadded<Event>,
+    slots: [UnsafeCell<MaybeUninit<T>>; N]
+}
+
+unsafe impl<T: Copy + Send, const N: usize> Sync for SpscQueue<T, N> {}
+
+unsafe impl<T, const N: usize> SharedLayout for SpscQueue<T, N>
+    where T: Copy + SharedLayout
+{
+    fn layout() -> TypeLayout {
+        TypeLayout::new::<Self>("SpscQueue")
+            .field("head", mem::offset_of!(Self, head), |s: &Self| &s.head)
+            .field("tail", mem::offset_of!(Self, tail), |s: &Self| &s.tail)
+            .field("readable", mem::offset_of!(Self, readable), |s: &Self| &s.readable)
+            .field("writable", mem::offset_of!(Self, writable), |s: &Self| &s.writable)
+            .field("slots", mem::offset_of!(Self, slots), |s: &Self| &s.slots)
+    }
+}
+
+impl<T, const N: usize> PthreadPrimitiveConstructor for SpscQueue<T, N>
+    where T: Copy
+{
+    fn new() -> Self {
+        assert!(N.is_power_of_two(), "queue capacity must be a power of two");
+        SpscQueue {
+            head: CachePadded(AtomicUsize::new(0)),
+            tail: CachePadded(AtomicUsize::new(0)),
+            readable: CachePadded(Event::new()),
+            writable: CachePadded(Event::new()),
+            slots: [const { UnsafeCell::new(MaybeUninit::uninit()) }; N]
+        }
+    }
+
+    // Futexes are process-shared as long as they live in shared memory
+    fn pshared() -> Self {
+        Self::new()
+    }
+}
+
+#[allow(dead_code)]
+impl<T, const N: usize> SpscQueue<T, N>
+    where T: Copy
+{
+    pub fn capacity(&self) -> usize {
+        N
+    }
+
+    /// Pushes `value` unless the queue is full, handing it back otherwise.
+    pub fn try_push(&self, value: T) -> Result<result::Result<(), Full<T>>> {
+        let tail = self.tail.0.load(Ordering::Relaxed);
+        if tail.wrapping_sub(self.head.0.load(Ordering::Acquire)) == N {
+            return Ok(Err(Full(value)));
+        }
+
+        unsafe {
+            (*self.slots[tail & (N - 1)].get()) = MaybeUninit::new(value);
+        }
+        self.tail.0.store(tail.wrapping_add(1), Ordering::Release);
+        self.readable.0.notify_all()?;
+        Ok(Ok(()))
+    }
+
+    pub fn push(&self, value: T) -> Result<()> {
+        match self.push_until(value, None)? {
+            Ok(()) => Ok(()),
+            Err(_) => unreachable!("push without deadline timed out")
+        }
+    }
+
+    pub fn timed_push(&self, value: T, time: Duration) -> Result<result::Result<(), Full<T>>> {
+        self.push_until(value, Some(Instant::now() + time))
+    }
+
+    pub fn push_deadline(&self, value: T, deadline: Instant) -> Result<result::Result<(), Full<T>>> {
+        self.push_until(value, Some(deadline))
+    }
+
+    fn push_until(&self, value: T, deadline: Option<Instant>) -> Result<result::Result<(), Full<T>>> {
+        let mut value = value;
+        loop {
+            match self.try_push(value)? {
+                Ok(()) => return Ok(Ok(())),
+                Err(Full(rejected)) => value = rejected
+            }
+
+            let key = self.writable.0.prepare();
+            if !self.is_full() {
+                self.writable.0.cancel();
+                continue;
+            }
+            match self.writable.0.wait(key, deadline) {
+                Err(Error::Sys(Errno::ETIMEDOUT)) => return self.try_push(value),
+                Err(err) => return Err(err),
+                Ok(()) => ()
+            }
+        }
+    }
+
+    pub fn try_pop(&self) -> Result<Option<T>> {
+        let head = self.head.0.load(Ordering::Relaxed);
+        if head == self.tail.0.load(Ordering::Acquire) {
+            return Ok(None);
+        }
+
+        let value = unsafe {
+            (*self.slots[head & (N - 1)].get()).assume_init()
+        };
+        self.head.0.store(head.wrapping_add(1), Ordering::Release);
+        self.writable.0.notify_all()?;
+        Ok(Some(value))
+    }
+
+    pub fn pop(&self) -> Result<T> {
+        loop {
+            if let Some(value) = self.pop_until(None)? {
+                return Ok(value);
+            }
+        }
+    }
+
+    pub fn timed_pop(&self, time: Duration) -> Result<Option<T>> {
+        self.pop_until(Some(Instant::now() + time))
+    }
+
+    pub fn pop_deadline(&self, deadline: Instant) -> Result<Option<T>> {
+        self.pop_until(Some(deadline))
+    }
+
+    fn pop_until(&self, deadline: Option<Instant>) -> Result<Option<T>> {
+        loop {
+            if let Some(value) = self.try_pop()? {
+                return Ok(Some(value));
+            }
+
+            let key = self.readable.0.prepare();
+            if !self.is_empty() {
+                self.readable.0.cancel();
+                continue;
+            }
+            match self.readable.0.wait(key, deadline) {
+                Err(Error::Sys(Errno::ETIMEDOUT)) => return self.try_pop(),
+                Err(err) => return Err(err),
+                Ok(()) => ()
+            }
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.head.0.load(Ordering::SeqCst) == self.tail.0.load(Ordering::SeqCst)
+    }
+
+    fn is_full(&self) -> bool {
+        self.tail.0.load(Ordering::SeqCst).wrapping_sub(self.head.0.load(Ordering::SeqCst)) == N
+    }
+}
+
+impl<T, const N: usize> BlockingQueue<T> for SpscQueue<T, N>
+    where T: Copy
+{
+    fn push(&self, value: T) -> Result<()> {
+        SpscQueue::push(self, value)
+    }
+
+    fn try_push(&self, value: T) -> Result<result::Result<(), Full<T>>> {
+        SpscQueue::try_push(self, value)
+    }
+
+    fn timed_push(&self, value: T, time: Duration) -> Result<result::Result<(), Full<T>>> {
+        SpscQueue::timed_push(self, value, time)
+    }
+
+    fn push_deadline(&self, value: T, deadline: Instant) -> Result<result::Result<(), Full<T>>> {
+        SpscQueue::push_deadline(self, value, deadline)
+    }
+
+    fn pop(&self) -> Result<T> {
+        SpscQueue::pop(self)
+    }
+
+    fn try_pop(&self) -> Result<Option<T>> {
+        SpscQueue::try_pop(self)
+    }
+
+    fn timed_pop(&self, time: Duration) -> Result<Option<T>> {
+        SpscQueue::timed_pop(self, time)
+    }
+
+    fn pop_deadline(&self, deadline: Instant) -> Result<Option<T>> {
+        SpscQueue::pop_deadline(self, deadline)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::SpscQueue;
+    use ::queue::Full;
+    use ::pthread::PthreadPrimitiveConstructor;
+    use ::process;
+    use ::shm::Shm;
+    use std::mem;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn cache_lines() {
+        let queue: SpscQueue<u8> = SpscQueue::new();
+        let head = &queue.head as *const _ as usize;
+        let tail = &queue.tail as *const _ as usize;
+        assert!(tail - head >= 64);
+        assert_eq!(0, mem::align_of::<SpscQueue<u8>>() % 64);
+    }
+
+    #[test]
+    fn token() {
+        let queue: Shm<SpscQueue<u32>> = Shm::new(SpscQueue::pshared()).unwrap();
+        let other = Shm::<SpscQueue<u32>>::from_token(&queue.token()).unwrap();
+        queue.push(1).unwrap();
+        assert_eq!(Ok(1), other.pop());
+    }
+
+    #[test]
+    fn try_push_pop() {
+        let queue: SpscQueue<i32, 2> = SpscQueue::new();
+        assert_eq!(Ok(None), queue.try_pop());
+        assert_eq!(Ok(Ok(())), queue.try_push(1));
+        assert_eq!(Ok(Ok(())), queue.try_push(2));
+        assert_eq!(Ok(Err(Full(3))), queue.try_push(3));
+        assert_eq!(Ok(Some(1)), queue.try_pop());
+        assert_eq!(Ok(Ok(())), queue.try_push(3));
+        assert_eq!(Ok(Some(2)), queue.try_pop());
+        assert_eq!(Ok(Some(3)), queue.try_pop());
+        assert_eq!(Ok(None), queue.try_pop());
+    }
+
+    #[test]
+    fn timed_pop() {
+        let queue: SpscQueue<i32> = SpscQueue::new();
+        let start = Instant::now();
+        assert_eq!(Ok(None), queue.timed_pop(Duration::from_millis(10)));
+        assert!(start.elapsed() >= Duration::from_millis(10));
+    }
+
+    #[test]
+    fn spsc() {
+        let queue: Shm<SpscQueue<_>> = Shm::new(SpscQueue::new()).unwrap();
+
+        let producer = {
+            let queue = queue.clone();
+            thread::spawn(move || {
+                for i in 0..10000 {
+                    queue.push(i).unwrap();
+                }
+            })
+        };
+
+        for i in 0..10000 {
+            assert_eq!(Ok(i), queue.pop());
+        }
+
+        producer.join().unwrap();
+    }
+
+    #[test]
+    fn spsc_ipc() {
+        let queue: Shm<SpscQueue<_>> = Shm::new(SpscQueue::pshared()).unwrap();
+
+        {
+            let queue = queue.clone();
+            process::spawn(move || {
+                for i in 0..10000 {
+                    queue.push(i).unwrap();
+                }
+            }).unwrap();
+        }
+
+        for i in 0..10000 {
+            assert_eq!(Ok(i), queue.pop());
+        }
+    }
+}